@@ -0,0 +1,263 @@
+use crate::lobby_cache::intern;
+use crate::lobby_cache::model::{Lobby, Slot, UpstreamFrame};
+use crate::lobby_cache::store::{LobbyStore, MemoryLobbyStore};
+use serde_derive::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::time::Instant;
+use tokio::time::Duration;
+
+const REGIONS: [&str; 8] = [
+    "westeurope",
+    "northeurope",
+    "eastus",
+    "westus",
+    "brazilsouth",
+    "southeastasia",
+    "australiasoutheast",
+    "koreacentral",
+];
+const CIVS: [&str; 8] = [
+    "Franks",
+    "Mongols",
+    "Britons",
+    "Huns",
+    "Aztecs",
+    "Byzantines",
+    "Mayans",
+    "Teutons",
+];
+const MAPS: [&str; 4] = ["Arabia", "Arena", "Nomad", "Black Forest"];
+
+// Builds a snapshot frame of synthetic lobbies, shaped like the ones aoe2lobby.com sends
+fn snapshot_frame(lobby_count: usize) -> String {
+    let mut lobbies = serde_json::Map::new();
+    for lobby_id in 0..lobby_count {
+        let slots_total = 2 + lobby_id % 7;
+        let slots_taken = 1 + lobby_id % slots_total;
+        let mut slot = serde_json::Map::new();
+        for position in 0..slots_taken {
+            slot.insert(
+                (position + 1).to_string(),
+                json!({
+                    "color": (position + 1).to_string(),
+                    "team": (position % 2 + 1).to_string(),
+                    "civ": CIVS[(lobby_id + position) % CIVS.len()],
+                    "name": format!("player-{}-{}", lobby_id, position),
+                    "profileid": lobby_id * 10 + position,
+                    "rating": 1000 + (lobby_id * 7 + position * 13) % 1500,
+                }),
+            );
+        }
+        lobbies.insert(
+            lobby_id.to_string(),
+            json!({
+                "maxplayers": 8,
+                "relayserver_region": REGIONS[lobby_id % REGIONS.len()],
+                "lobbyid": lobby_id,
+                "description": format!("lobby {} come play", lobby_id),
+                "slotstaken": slots_taken,
+                "slotstotal": slots_total,
+                "slot": slot,
+                "map": MAPS[lobby_id % MAPS.len()],
+                "gametype": "Random Map",
+                "speed": "Normal",
+                "hostprofileid": lobby_id * 10,
+            }),
+        );
+    }
+    json!({ "allcurrentlobbies": lobbies }).to_string()
+}
+
+// Resident memory of the process, only available on linux
+fn resident_bytes() -> Option<u64> {
+    let statm = fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    Some(pages * 4096)
+}
+
+fn format_bytes(bytes: Option<u64>) -> String {
+    match bytes {
+        Some(bytes) => format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0)),
+        None => "n/a".to_string(),
+    }
+}
+
+// The lobby model as it was before strings were interned and lobbies shared, every read deep
+// copied one of these. Only built to be measured, so its fields are never read
+#[allow(dead_code)]
+#[derive(Deserialize)]
+struct OwnedLobby {
+    maxplayers: i64,
+    relayserver_region: String,
+    lobbyid: i64,
+    description: String,
+    slotstaken: i64,
+    slotstotal: i64,
+    slot: HashMap<String, OwnedSlot>,
+    map: Option<String>,
+    gametype: Option<String>,
+    speed: Option<String>,
+    passwordprotected: Option<bool>,
+    host: Option<String>,
+    hostprofileid: Option<i64>,
+    spectatable: Option<bool>,
+    spectatorcount: Option<i64>,
+    spectatordelay: Option<i64>,
+    #[serde(flatten)]
+    extra: HashMap<String, Value>,
+}
+
+#[allow(dead_code)]
+#[derive(Deserialize)]
+struct OwnedSlot {
+    color: String,
+    team: Option<String>,
+    civ: String,
+    name: Option<String>,
+    playerid: Option<i64>,
+    profileid: Option<i64>,
+    rating: Option<i64>,
+    spectator: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct OwnedSnapshot {
+    allcurrentlobbies: HashMap<String, OwnedLobby>,
+}
+
+impl From<&Lobby> for OwnedLobby {
+    fn from(lobby: &Lobby) -> Self {
+        Self {
+            maxplayers: lobby.maxplayers,
+            relayserver_region: lobby.relayserver_region.to_string(),
+            lobbyid: lobby.lobbyid,
+            description: lobby.description.clone(),
+            slotstaken: lobby.slotstaken,
+            slotstotal: lobby.slotstotal,
+            slot: lobby
+                .slot
+                .iter()
+                .map(|(position, slot)| (position.clone(), OwnedSlot::from(slot)))
+                .collect(),
+            map: lobby.map.clone(),
+            gametype: lobby.gametype.clone(),
+            speed: lobby.speed.clone(),
+            passwordprotected: lobby.passwordprotected,
+            host: lobby.host.clone(),
+            hostprofileid: lobby.hostprofileid,
+            spectatable: lobby.spectatable,
+            spectatorcount: lobby.spectatorcount,
+            spectatordelay: lobby.spectatordelay,
+            extra: lobby.extra.clone(),
+        }
+    }
+}
+
+impl From<&Slot> for OwnedSlot {
+    fn from(slot: &Slot) -> Self {
+        Self {
+            color: slot.color.to_string(),
+            team: slot.team.as_ref().map(|team| team.to_string()),
+            civ: slot.civ.to_string(),
+            name: slot.name.clone(),
+            playerid: slot.playerid,
+            profileid: slot.profileid,
+            rating: slot.rating,
+            spectator: slot.spectator,
+        }
+    }
+}
+
+fn resident_growth(before: Option<u64>, after: Option<u64>) -> String {
+    match (before, after) {
+        (Some(before), Some(after)) => format_bytes(Some(after.saturating_sub(before))),
+        _ => format_bytes(None),
+    }
+}
+
+// Loads synthetic lobbies into a store, then reads them the way watchers do: through shared
+// handles, and through deep copies of the owned model as the cache did before lobbies were shared
+pub async fn run(lobby_count: usize, watcher_count: usize) -> i32 {
+    println!(
+        "Benchmarking {} lobbies read by {} watchers",
+        lobby_count, watcher_count
+    );
+    let frame = snapshot_frame(lobby_count);
+    let resident_before = resident_bytes();
+
+    let started = Instant::now();
+    let lobbies = match UpstreamFrame::parse(frame.as_bytes()).map(|parsed| parsed.frame) {
+        Ok(UpstreamFrame::AllCurrentLobbies(frame)) => frame.allcurrentlobbies,
+        other => {
+            eprintln!(
+                "Synthetic frame didn't parse as a snapshot: {:?}",
+                other.err()
+            );
+            return 1;
+        }
+    };
+    let store = MemoryLobbyStore::new(Duration::from_secs(60), Duration::from_secs(60));
+    store.apply(lobbies, vec![], true).await;
+    let resident_stored = resident_bytes();
+    println!("Parsed and stored in {:?}", started.elapsed());
+
+    // The same frame parsed into the model without interning, for comparison
+    let owned = match serde_json::from_str::<OwnedSnapshot>(&frame) {
+        Ok(owned) => owned,
+        Err(e) => {
+            eprintln!("Synthetic frame didn't parse as owned lobbies: {:?}", e);
+            return 1;
+        }
+    };
+    let resident_owned = resident_bytes();
+    let owned_count = owned.allcurrentlobbies.len();
+    drop(owned);
+    drop(frame);
+    println!(
+        "Resident memory growth: {} for the stored lobbies, {} for the same {} lobbies with owned strings",
+        resident_growth(resident_before, resident_stored),
+        resident_growth(resident_stored, resident_owned),
+        owned_count
+    );
+    println!(
+        "Interned fields: {} distinct strings",
+        intern::interned_count()
+    );
+
+    let lobby_ids: Vec<String> = (0..lobby_count).map(|id| id.to_string()).collect();
+    let started = Instant::now();
+    let mut shared = Vec::with_capacity(watcher_count);
+    for watcher in 0..watcher_count {
+        shared.push(store.get(&lobby_ids[watcher % lobby_count]));
+    }
+    let shared_elapsed = started.elapsed();
+    let shared_resident = resident_bytes();
+    drop(shared);
+
+    let started = Instant::now();
+    let mut copied: Vec<Option<OwnedLobby>> = Vec::with_capacity(watcher_count);
+    for watcher in 0..watcher_count {
+        copied.push(
+            store
+                .get(&lobby_ids[watcher % lobby_count])
+                .map(|lobby| OwnedLobby::from(&*lobby)),
+        );
+    }
+    let copied_elapsed = started.elapsed();
+    let copied_resident = resident_bytes();
+    drop(copied);
+
+    println!(
+        "Shared reads: {:?}, resident memory {} while held",
+        shared_elapsed,
+        format_bytes(shared_resident)
+    );
+    println!(
+        "Deep copied reads: {:?}, resident memory {} while held",
+        copied_elapsed,
+        format_bytes(copied_resident)
+    );
+    0
+}
@@ -0,0 +1,52 @@
+// Scripted stand-in for wss://aoe2lobby.com/ws/lobby/, used to exercise the bot without network access.
+//
+// Usage: mock_aoe2lobby <script.jsonl> [listen address, default 127.0.0.1:9001]
+//
+// The script holds one step per line. Steps are played back in order for every connection,
+// `"next_connection"` starts the steps for the following connection, and the last block is
+// replayed for every connection after that:
+//
+//   {"send": {"allcurrentlobbies": {...}}}  send a json frame
+//   {"send_raw": "not json"}                send a text frame as is
+//   {"sleep_ms": 1000}                      wait before the next step
+//   "drop"                                  drop the tcp connection without a close frame
+//   "close"                                 close the websocket gracefully
+//   "silence"                               keep the connection open without sending anything
+//   "next_connection"                       end of the steps for the current connection
+//
+// When the steps of a connection run out, the connection goes silent.
+
+#[path = "../mock_upstream.rs"]
+mod mock_upstream;
+
+use std::env;
+use std::fs;
+
+use tokio::net::TcpListener;
+use tracing::info;
+use tracing_subscriber::EnvFilter;
+
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
+
+    let mut args = env::args().skip(1);
+    let script_path = args
+        .next()
+        .expect("Usage: mock_aoe2lobby <script.jsonl> [listen address]");
+    let addr = args.next().unwrap_or_else(|| "127.0.0.1:9001".to_string());
+
+    let content = fs::read_to_string(&script_path)
+        .unwrap_or_else(|e| panic!("Failed to read {}: {}", script_path, e));
+    let script = mock_upstream::parse_script(&script_path, &content);
+    let listener = TcpListener::bind(&addr)
+        .await
+        .unwrap_or_else(|e| panic!("Failed to bind {}: {}", addr, e));
+    info!("Serving {} on ws://{}", script_path, addr);
+
+    mock_upstream::run(listener, script).await;
+}
@@ -0,0 +1,91 @@
+use crate::bench;
+use crate::lobby_cache::dead_letter::{list_dead_letters, read_dead_letter, reparse};
+use std::fs;
+use std::path::Path;
+use std::process;
+
+const USAGE: &str =
+    "Usage: lobby-is-up [reparse-dead-letters <dir> [--remove-fixed] | bench [lobbies] [watchers]]";
+
+// Runs the subcommand named by the first argument, the bot itself runs when there is none
+pub async fn run(args: &[String]) -> Option<i32> {
+    let command = args.get(1)?;
+    let code = match command.as_str() {
+        "reparse-dead-letters" => match args.get(2) {
+            Some(dir) => reparse_dead_letters(
+                Path::new(dir),
+                args[3..].iter().any(|arg| arg == "--remove-fixed"),
+            ),
+            None => usage(),
+        },
+        "bench" => {
+            let lobby_count = count_arg(args.get(2), 5000);
+            let watcher_count = count_arg(args.get(3), 100_000);
+            match (lobby_count, watcher_count) {
+                (Some(lobby_count), Some(watcher_count)) => {
+                    bench::run(lobby_count, watcher_count).await
+                }
+                _ => usage(),
+            }
+        }
+        "help" | "--help" | "-h" => {
+            println!("{}", USAGE);
+            0
+        }
+        _ => usage(),
+    };
+    Some(code)
+}
+
+// A positive count, or the default when the argument is missing
+fn count_arg(arg: Option<&String>, default: usize) -> Option<usize> {
+    match arg {
+        Some(arg) => arg.parse().ok().filter(|count| *count > 0),
+        None => Some(default),
+    }
+}
+
+fn usage() -> i32 {
+    eprintln!("{}", USAGE);
+    2
+}
+
+// Parses every dead letter again with the current model, exits with 1 if any still fails
+fn reparse_dead_letters(dir: &Path, remove_fixed: bool) -> i32 {
+    let paths = list_dead_letters(dir).unwrap_or_else(|e| {
+        eprintln!("Failed to list {}: {}", dir.display(), e);
+        process::exit(1)
+    });
+
+    let mut failed = 0;
+    for path in &paths {
+        let dead_letter = match read_dead_letter(path) {
+            Ok(dead_letter) => dead_letter,
+            Err(e) => {
+                eprintln!("{}: unreadable dead letter: {}", path.display(), e);
+                failed += 1;
+                continue;
+            }
+        };
+        match reparse(&dead_letter) {
+            Ok(frame) => {
+                println!("{}: parses as {}", path.display(), frame);
+                if remove_fixed {
+                    if let Err(e) = fs::remove_file(path) {
+                        eprintln!("{}: failed to remove: {}", path.display(), e);
+                    }
+                }
+            }
+            Err(e) => {
+                println!("{}: still fails: {}", path.display(), e);
+                failed += 1;
+            }
+        }
+    }
+    println!("{} of {} dead letters still fail", failed, paths.len());
+    if failed > 0 {
+        1
+    } else {
+        0
+    }
+}
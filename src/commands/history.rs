@@ -0,0 +1,95 @@
+use crate::commands::lobby::extract_lobby_id;
+use crate::commands::util::{create_embed_response, create_interaction_response, escape_markdown};
+use crate::lobby_cache::event::DeletionReason;
+use crate::lobby_cache::history::{HistoryEntry, HistoryKind, HistoryStore};
+use serenity::builder::CreateApplicationCommand;
+use serenity::client::Context;
+use serenity::model::application::interaction::application_command::ApplicationCommandInteraction;
+use serenity::model::prelude::command::CommandOptionType;
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use tracing::error;
+
+const MAX_ENTRIES: usize = 25;
+
+pub struct HistoryHandler {
+    // None when history isn't recorded
+    history: Option<Arc<HistoryStore>>,
+}
+
+impl HistoryHandler {
+    pub fn new(history: Option<Arc<HistoryStore>>) -> Self {
+        Self { history }
+    }
+
+    pub async fn run(&self, ctx: &Context, command: ApplicationCommandInteraction) {
+        let history = match &self.history {
+            Some(history) => history,
+            None => {
+                create_interaction_response(
+                    ctx,
+                    command,
+                    "Lobby history is not enabled".to_string(),
+                )
+                .await;
+                return;
+            }
+        };
+        let lobby_id = match extract_lobby_id(&command.data.options)
+            .and_then(|lobby_id| lobby_id.split('/').next_back()?.parse::<i64>().ok())
+        {
+            Some(lobby_id) => lobby_id,
+            None => {
+                create_interaction_response(ctx, command, "Invalid lobby id".to_string()).await;
+                return;
+            }
+        };
+
+        let lines = match history.timeline(lobby_id, MAX_ENTRIES).await {
+            Ok(entries) if entries.is_empty() => vec!["No history for this lobby".to_string()],
+            Ok(entries) => entries.iter().map(format_entry).collect(),
+            Err(e) => {
+                error!("Failed to read lobby history: {:?}", e);
+                vec!["Failed to read lobby history".to_string()]
+            }
+        };
+        let title = format!("aoe2de://0/{}", lobby_id);
+        create_embed_response(ctx, command, &title, lines).await;
+    }
+
+    pub fn register(command: &mut CreateApplicationCommand) -> &mut CreateApplicationCommand {
+        command
+            .name("history")
+            .description("Who joined and left an Aoe2 lobby")
+            .create_option(|option| {
+                option
+                    .name("lobby_id")
+                    .description("The lobby id(ex: aoe2de://0/123456789)")
+                    .kind(CommandOptionType::String)
+                    .required(true)
+            })
+    }
+}
+
+fn format_entry(entry: &HistoryEntry) -> String {
+    // Discord renders the timestamp in the reader's timezone
+    let timestamp = entry
+        .timestamp
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let player = escape_markdown(entry.player.as_deref().unwrap_or("Unknown"));
+    let detail = escape_markdown(entry.detail.as_deref().unwrap_or_default());
+    let event = match entry.kind {
+        HistoryKind::Created => format!("Lobby created: {}", detail),
+        HistoryKind::Joined => format!("{} joined", player),
+        HistoryKind::Left => format!("{} left", player),
+        HistoryKind::DescriptionChanged => format!("Description changed to: {}", detail),
+        HistoryKind::Deleted => match entry.detail.as_deref().and_then(DeletionReason::parse) {
+            Some(DeletionReason::Launched) => "Game started".to_string(),
+            Some(DeletionReason::Abandoned) => "Lobby abandoned".to_string(),
+            Some(DeletionReason::Closed) | None => "Lobby closed".to_string(),
+        },
+    };
+    format!("<t:{}:T> {}", timestamp, event)
+}
@@ -1,136 +1,79 @@
-use crate::lobby_cache::LobbyCache;
-use std::collections::HashMap;
+use crate::lobby_cache::event::DeletionReason;
+use crate::lobby_cache::store::{LobbyStore, WatchedLobby};
 
+use crate::commands::scheduler::EditScheduler;
+use crate::commands::tracked::{TrackedLobby, TrackedMessage, TrackedStore};
+use crate::commands::tracker::{ButtonAction, ButtonOutcome, LobbyTrackers};
 use crate::commands::util::create_interaction_response;
+use crate::lobby_cache::intern::Symbol;
 use crate::lobby_cache::model::Lobby;
+use crate::lobby_cache::reconnect::CircuitState;
+use crate::lobby_cache::state::ConnectionState;
 
 use once_cell::sync::Lazy;
 use regex::Regex;
-use serenity::builder::{CreateApplicationCommand, CreateEmbed};
+use serenity::builder::{CreateActionRow, CreateApplicationCommand, CreateComponents, CreateEmbed};
 use serenity::client::Context;
+use serenity::model::application::component::ButtonStyle;
 use serenity::model::application::interaction::application_command::{
     ApplicationCommandInteraction, CommandDataOption,
 };
+use serenity::model::application::interaction::message_component::MessageComponentInteraction;
 use serenity::model::application::interaction::InteractionResponseType;
 use serenity::model::prelude::command::CommandOptionType;
 
-use scopeguard::defer;
 use serenity::utils::{Color, Colour};
 use std::sync::Arc;
+use std::time::SystemTime;
 
 use crate::commands::error;
-use tokio::sync::mpsc::{Receiver, Sender};
-use tokio::sync::Mutex;
-use tokio::time::sleep;
+use crate::metrics;
+use tokio::time::timeout;
 use tokio::time::Duration;
-use tracing::error;
 use tracing::log::debug;
+use tracing::{error, info, warn};
 use uuid::Uuid;
 
 static RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"^aoe2de://0/\d+$").unwrap());
 
-type ChannelQueue = Arc<Mutex<HashMap<String, Vec<(Uuid, Arc<Sender<()>>)>>>>;
-
 pub struct LobbyHandler {
-    lobby_cache: Arc<LobbyCache>,
-    channel_queue: ChannelQueue,
+    lobby_store: Arc<dyn LobbyStore>,
+    trackers: LobbyTrackers,
 }
 
 impl LobbyHandler {
-    pub fn new(lobby_cache: Arc<LobbyCache>) -> Self {
+    pub fn new(
+        lobby_store: Arc<dyn LobbyStore>,
+        tracked_store: TrackedStore,
+        scheduler: EditScheduler,
+    ) -> Self {
         Self {
-            lobby_cache,
-            channel_queue: Arc::new(Mutex::new(HashMap::new())),
-        }
-    }
-
-    async fn register_lobby_id_channel(
-        &self,
-        game_id: &str,
-    ) -> error::Result<(Uuid, Receiver<()>)> {
-        let mut channel_queue = self.channel_queue.lock().await;
-        if channel_queue.len() > 5 {
-            return Err(error::CommandError::TooManyLobbies);
-        }
-
-        let queue = channel_queue
-            .entry(game_id.to_string())
-            .or_insert(Vec::new());
-        if queue.len() > 3 {
-            let (_, sender) = queue.pop().unwrap();
-            sender.send(()).await.unwrap();
-        }
-
-        let (sender, receiver) = tokio::sync::mpsc::channel(3);
-
-        let uuid = Uuid::new_v4();
-
-        queue.insert(0, (uuid, Arc::new(sender)));
-
-        Ok((uuid, receiver))
-    }
-
-    async fn unregister(channel_queue: ChannelQueue, game_id: &str, uuid: Uuid) {
-        let mut channel_queue = channel_queue.lock().await;
-        let queue = channel_queue.get_mut(game_id).unwrap();
-        queue.retain(|(id, _)| id != &uuid);
-        if queue.is_empty() {
-            channel_queue.remove(game_id);
+            trackers: LobbyTrackers::new(lobby_store.clone(), tracked_store, scheduler),
+            lobby_store,
         }
     }
 
-    fn get_lobby(&self, game_id: &str) -> Option<Lobby> {
-        return self
-            .lobby_cache
-            .lobby_cache
-            .get(game_id)
-            .map(|lobby_ref| lobby_ref.clone());
-    }
-
     pub async fn run(&self, ctx: &Context, command: ApplicationCommandInteraction) {
         let options = &command.data.options;
         if let Some(lobby_id) = extract_lobby_id(options) {
             debug!("Lobby ID: {}", lobby_id);
-            {
-                let last_update = self.lobby_cache.last_update.lock().await;
-                if last_update.is_none()
-                    || last_update.unwrap().elapsed().unwrap() > Duration::from_secs(60)
-                {
-                    create_interaction_response(
-                        ctx,
-                        command,
-                        "aoe2lobby.com hasn't replied in over a minute. Try again later..."
-                            .to_string(),
-                    )
+            let connection_state = self.lobby_store.connection_state();
+            if !connection_state.is_serving() || !self.lobby_store.is_fresh().await {
+                create_interaction_response(ctx, command, unavailable_message(&connection_state))
                     .await;
-                    return;
-                }
+                return;
             }
 
             let game_id = lobby_id.clone();
-            let game_id = game_id.split('/').last().unwrap();
+            let game_id = game_id.split('/').next_back().unwrap();
 
-            let (uuid, mut cancel_receiver) = match self.register_lobby_id_channel(game_id).await {
-                Ok(receiver) => receiver,
-                Err(error) => {
-                    create_interaction_response(ctx, command, format!("{}", error)).await;
-                    return;
-                }
-            };
-
-            let channel_queue_clone = self.channel_queue.clone();
-            let game_id_clone = game_id.to_string();
-            let uuid_clone = uuid;
-
-            defer! {
-                tokio::spawn(async move {
-                    Self::unregister(channel_queue_clone, game_id_clone.as_str(), uuid_clone).await
-                });
+            if !self.trackers.has_room(game_id).await {
+                let error = error::CommandError::TooManyLobbies;
+                create_interaction_response(ctx, command, format!("{}", error)).await;
+                return;
             }
 
-            // Discord allows for up to 15 minutes for a response
-            let deadline = tokio::time::Instant::now() + Duration::from_secs(14 * 60);
-
+            let started_at = SystemTime::now();
             if let Err(why) = command
                 .create_interaction_response(&ctx.http, |response| {
                     response
@@ -147,154 +90,235 @@ impl LobbyHandler {
                 .await
             {
                 error!("Cannot respond to slash command: {:?}", why);
+                metrics::DISCORD_EDIT_FAILURES.inc();
 
                 return;
             }
 
-            let mut result: Option<Lobby> = None;
+            let mut lobby_watch = self.lobby_store.watch(game_id);
+
+            // aoe2lobby.com can take a while to pick up a freshly created lobby
+            let picked_up = matches!(
+                timeout(
+                    Duration::from_secs(30),
+                    lobby_watch.wait_for(|lobby| *lobby != WatchedLobby::Missing),
+                )
+                .await,
+                Ok(Ok(_))
+            );
+
+            if !picked_up {
+                if let Err(why) = command
+                    .edit_original_interaction_response(&ctx.http, |response| {
+                        response.embed(|embed| {
+                            embed
+                                .title(lobby_id.to_string())
+                                .url(format!("https://aoe2lobby.com/j/{}", game_id))
+                                .description("aoe2lobby.com hasn't picked up this lobby after 30 seconds.\nPlayer data will be unavailable.");
+                            embed
+                        })
+                    })
+                    .await
+                {
+                    error!("Cannot respond to slash command: {:?}", why);
+                    metrics::DISCORD_EDIT_FAILURES.inc();
+                }
+                return;
+            }
 
-            for _ in 0..10 {
-                if let Some(value) = self.get_lobby(game_id) {
-                    debug!("Aoe2 Registered lobby with id: {}", value.lobbyid);
-                    result = Some(value);
-                    break;
-                } else {
-                    debug!("Retrying...");
-                    sleep(Duration::from_secs(3)).await; // optional delay
+            let tracked = TrackedLobby {
+                lobby_id: game_id.to_string(),
+                requester: command.user.id,
+                started_at,
+                message: TrackedMessage::Interaction {
+                    token: command.token.clone(),
+                    channel_id: command.channel_id,
+                    interaction_id: Some(command.id),
+                },
+            };
+            // Other lobbies may have been tracked while waiting for this one
+            if let Err(error) = self.trackers.attach(ctx, Uuid::new_v4(), tracked).await {
+                if let Err(why) = command
+                    .edit_original_interaction_response(&ctx.http, |response| {
+                        response.content(format!("{}", error)).set_embeds(vec![])
+                    })
+                    .await
+                {
+                    error!("Cannot respond to slash command: {:?}", why);
+                    metrics::DISCORD_EDIT_FAILURES.inc();
                 }
             }
+        } else {
+            create_interaction_response(ctx, command, "Invalid lobby id".to_string()).await;
+        }
+    }
 
-            match result {
-                Some(lobby) => {
-                    let mut state = extract_state(&lobby);
-                    if let Err(why) = command
-                        .edit_original_interaction_response(&ctx.http, |response| {
-                            response.set_embed(create_embed(&state))
-                        })
-                        .await
-                    {
-                        error!("Cannot respond to slash command: {:?}", why);
-                        return;
-                    }
+    // Re-attaches the lobbies tracked before a restart, once the cache serves lobbies from
+    // upstream again
+    pub async fn resume(self: Arc<Self>, ctx: Context) {
+        let tracked_lobbies = self.trackers.tracked_store().list();
+        if tracked_lobbies.is_empty() {
+            return;
+        }
+        // A lobby missing from a cache that isn't serving yet didn't necessarily end, and neither
+        // did one missing from a restored snapshot, it may have been created after it was taken
+        let mut connection_state = self.lobby_store.watch_connection_state();
+        if connection_state
+            .wait_for(|state| state.is_serving() && !self.lobby_store.is_provisional())
+            .await
+            .is_err()
+        {
+            return;
+        }
 
-                    let mut update_receiver = self.lobby_cache.subscribe();
-                    loop {
-                        debug!("Inside of updater loop");
-                        tokio::select! {
-                            _ = cancel_receiver.recv() => {
-                                debug!("Received cancel");
-                                let mut last_embed = create_embed(&state);
-                                {
-                                    last_embed.footer(|footer| footer.text("Message no longer updated live"));
-                                }
-
-                                if let Err(why) = command
-                                    .edit_original_interaction_response(&ctx.http, |response| {
-                                        response.set_embed(last_embed)
-                                    })
-                                    .await
-                                {
-                                    error!("Cannot respond to slash command: {:?}", why);
-                                    return;
-                                }
-                                break;
-                            }
-                            _ = tokio::time::sleep_until(deadline) => {
-                                let mut last_embed = create_embed(&state);
-                                {
-                                    last_embed.footer(|footer| footer.text("Message no longer updated live, as it was up for over 15 minutes"));
-
-                                }
-                                if let Err(why) = command
-                                    .edit_original_interaction_response(&ctx.http, |response| {
-                                        response.set_embed(last_embed)
-                                    })
-                                    .await
-                                {
-                                    error!("Cannot respond to slash command: {:?}", why);
-                                    return;
-                                }
-                                break;
-                            }
-                            _ = update_receiver.recv() => {
-                                debug!("Received update");
-                            }
-                            _ = sleep(Duration::from_secs(10)) => {
-                                debug!("Mandatory update");
-                            }
-                        }
-
-
-
-                        debug!("Attempting to update interaction response");
-
-                        match self.get_lobby(game_id) {
-                            None => {
-                                debug!("Lobby no longer running");
-
-                                if let Err(why) = command
-                                    .edit_original_interaction_response(&ctx.http, |response| {
-                                        response.embed(|embed| {
-                                            embed
-                                                .title(lobby_id.to_string())
-                                                .url(format!("https://aoe2lobby.com/j/{}", game_id))
-                                                .description("Lobby no longer active");
-                                            embed
-                                        })
-                                    })
-                                    .await
-                                {
-                                    error!("Cannot respond to slash command: {:?}", why);
-                                    break;
-                                }
-
-                                break;
-                            }
-
-                            Some(lobby) => {
-                                let _new_state = extract_state(&lobby);
-                                debug!("Lobby still running");
-                                let new_state = extract_state(&lobby);
-                                if new_state == state {
-                                    debug!("No change in state");
-                                    continue;
-                                } else {
-                                    debug!("Change in players");
-                                    state = new_state;
-                                }
-                                if let Err(why) = command
-                                    .edit_original_interaction_response(&ctx.http, |response| {
-                                        response.set_embed(create_embed(&state))
-                                    })
-                                    .await
-                                {
-                                    error!("Cannot respond to slash command: {:?}", why);
-                                    break;
-                                }
-                            }
-                        }
+        info!("Resuming {} tracked lobbies", tracked_lobbies.len());
+        for (uuid, tracked) in tracked_lobbies {
+            let lobby_id = tracked.lobby_id.clone();
+            let message = tracked.message.clone();
+            if let Err(error) = self.trackers.attach(&ctx, uuid, tracked).await {
+                warn!("Cannot resume tracking lobby {}: {}", lobby_id, error);
+                let last_embed = match self.lobby_store.get(&lobby_id) {
+                    Some(lobby) => {
+                        let state = extract_state(&lobby, self.lobby_store.is_provisional());
+                        let mut last_embed = create_embed(&state);
+                        last_embed.footer(|footer| footer.text("Message no longer updated live"));
+                        last_embed
                     }
+                    None => create_inactive_embed(&lobby_id),
+                };
+                if let Err(why) = message
+                    .edit(&ctx, last_embed, CreateComponents::default())
+                    .await
+                {
+                    error!(
+                        "Cannot edit a message tracked before the restart: {:?}",
+                        why
+                    );
+                    metrics::DISCORD_EDIT_FAILURES.inc();
                 }
-                None => {
-                    if let Err(why) = command
-                        .edit_original_interaction_response(&ctx.http, |response| {
-                            response.embed(|embed| {
-                                embed
-                                    .title(lobby_id.to_string())
-                                    .url(format!("https://aoe2lobby.com/j/{}", game_id))
-                                    .description("aoe2lobby.com hasn't picked up this lobby after 30 seconds.\nPlayer data will be unavailable.");
-                                embed
+                self.trackers.tracked_store().remove(uuid);
+            }
+        }
+    }
+
+    // Handles the buttons of lobby embeds, their custom id is lobby:<action>:<lobby id>
+    pub async fn run_component(&self, ctx: &Context, component: MessageComponentInteraction) {
+        let mut parts = component.data.custom_id.splitn(3, ':').skip(1);
+        let (action, lobby_id) = match (parts.next(), parts.next()) {
+            (Some(action), Some(lobby_id)) => (action, lobby_id),
+            _ => {
+                warn!("Unknown component: {}", component.data.custom_id);
+                return;
+            }
+        };
+        let action = match action {
+            "refresh" => ButtonAction::Refresh,
+            "stop" => ButtonAction::Stop {
+                moderator: component
+                    .member
+                    .as_ref()
+                    .and_then(|member| member.permissions)
+                    .map_or(false, |permissions| permissions.manage_messages()),
+            },
+            _ => {
+                warn!("Unknown lobby button: {}", component.data.custom_id);
+                return;
+            }
+        };
+        let stopping = matches!(action, ButtonAction::Stop { .. });
+
+        let outcome = self
+            .trackers
+            .press(
+                lobby_id,
+                action,
+                component.message.clone(),
+                component.user.id,
+            )
+            .await;
+        let result = match outcome {
+            // The tracker edits the message itself
+            ButtonOutcome::Done => {
+                component
+                    .create_interaction_response(&ctx.http, |response| {
+                        response.kind(InteractionResponseType::DeferredUpdateMessage)
+                    })
+                    .await
+            }
+            ButtonOutcome::NotAllowed => {
+                component
+                    .create_interaction_response(&ctx.http, |response| {
+                        response
+                            .kind(InteractionResponseType::ChannelMessageWithSource)
+                            .interaction_response_data(|message| {
+                                message
+                                    .content("Only the requester or a moderator can stop tracking this lobby")
+                                    .ephemeral(true)
                             })
-                        })
-                        .await
-                    {
-                        error!("Cannot respond to slash command: {:?}", why);
-                    }
-                }
+                    })
+                    .await
             }
-        } else {
-            create_interaction_response(ctx, command, "Invalid lobby id".to_string()).await;
+            ButtonOutcome::NotTracked if stopping => {
+                component
+                    .create_interaction_response(&ctx.http, |response| {
+                        response
+                            .kind(InteractionResponseType::ChannelMessageWithSource)
+                            .interaction_response_data(|message| {
+                                message
+                                    .content("This lobby isn't updated live anymore")
+                                    .ephemeral(true)
+                            })
+                    })
+                    .await
+            }
+            // Messages no longer updated live, e.g. after a restart, can still be refreshed by hand
+            ButtonOutcome::NotTracked => self.refresh(ctx, &component, lobby_id).await,
+        };
+        if let Err(why) = result {
+            error!("Cannot respond to button: {:?}", why);
+            metrics::DISCORD_EDIT_FAILURES.inc();
+        }
+    }
+
+    async fn refresh(
+        &self,
+        ctx: &Context,
+        component: &MessageComponentInteraction,
+        lobby_id: &str,
+    ) -> serenity::Result<()> {
+        let connection_state = self.lobby_store.connection_state();
+        if !connection_state.is_serving() || !self.lobby_store.is_fresh().await {
+            return component
+                .create_interaction_response(&ctx.http, |response| {
+                    response
+                        .kind(InteractionResponseType::ChannelMessageWithSource)
+                        .interaction_response_data(|message| {
+                            message
+                                .content(unavailable_message(&connection_state))
+                                .ephemeral(true)
+                        })
+                })
+                .await;
         }
+
+        let (embed, components) = match self.lobby_store.get(lobby_id) {
+            Some(lobby) => {
+                let state = extract_state(&lobby, self.lobby_store.is_provisional());
+                let mut embed = create_embed(&state);
+                embed.footer(|footer| footer.text("Message no longer updated live"));
+                (embed, create_components(&state, false))
+            }
+            None => (create_inactive_embed(lobby_id), CreateComponents::default()),
+        };
+        component
+            .create_interaction_response(&ctx.http, |response| {
+                response
+                    .kind(InteractionResponseType::UpdateMessage)
+                    .interaction_response_data(|message| {
+                        message.set_embed(embed).set_components(components)
+                    })
+            })
+            .await
     }
 
     pub fn register(command: &mut CreateApplicationCommand) -> &mut CreateApplicationCommand {
@@ -311,6 +335,33 @@ impl LobbyHandler {
     }
 }
 
+// Explains why lobbies can't be served in the given state
+pub fn unavailable_message(state: &ConnectionState) -> String {
+    let status = match state {
+        ConnectionState::Connecting | ConnectionState::Synchronizing => {
+            return "Connecting to aoe2lobby.com. Try again in a few seconds...".to_string()
+        }
+        ConnectionState::Live | ConnectionState::Degraded { .. } => {
+            return "aoe2lobby.com hasn't replied in over a minute. Try again later...".to_string()
+        }
+        ConnectionState::Disconnected(status) => status,
+    };
+    let retry_in = status
+        .next_retry
+        .and_then(|next_retry| next_retry.duration_since(SystemTime::now()).ok())
+        .unwrap_or_default();
+    match status.circuit {
+        CircuitState::Open => format!(
+            "aoe2lobby.com is down, retrying in {} s. Try again later...",
+            retry_in.as_secs()
+        ),
+        CircuitState::Closed => format!(
+            "Lost connection to aoe2lobby.com, reconnecting in {} s. Try again later...",
+            retry_in.as_secs()
+        ),
+    }
+}
+
 pub fn extract_lobby_id(options: &[CommandDataOption]) -> Option<String> {
     if let Some(command_application) = options.first() {
         if let Some(value) = &command_application.value {
@@ -326,66 +377,140 @@ pub fn extract_lobby_id(options: &[CommandDataOption]) -> Option<String> {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
-struct State {
+pub struct State {
     players: String,
     description: String,
-    relayserver_region: String,
+    relayserver_region: Symbol,
+    map: Option<String>,
+    game_type: Option<String>,
+    speed: Option<String>,
+    password_protected: bool,
+    spectators: Option<i64>,
     color: Color,
     slots_taken: i64,
     slots_total: i64,
     id: String,
+    provisional: bool,
 }
 
-fn extract_state(lobby: &Lobby) -> State {
+pub fn extract_state(lobby: &Lobby, provisional: bool) -> State {
     State {
         players: format_players(lobby),
         description: lobby.description.clone(),
         relayserver_region: lobby.relayserver_region.clone(),
+        map: lobby.map.clone(),
+        game_type: lobby.gametype.clone(),
+        speed: lobby.speed.clone(),
+        password_protected: lobby.passwordprotected.unwrap_or(false),
+        spectators: lobby.spectatorcount,
         color: extract_colors(lobby),
         slots_taken: lobby.slotstaken,
         slots_total: lobby.slotstotal,
         id: lobby.lobbyid.clone().to_string(),
+        provisional,
     }
 }
 
-fn create_embed(state: &State) -> CreateEmbed {
+pub fn create_embed(state: &State) -> CreateEmbed {
     let mut embed = CreateEmbed::default();
     let remaining_slots = state.slots_total - state.slots_taken;
-    let remaining_slots = if remaining_slots > 0 {
+    let mut remaining_slots = if remaining_slots > 0 {
         format!("+{}", remaining_slots)
     } else {
         "Lobby is full".to_string()
     };
+    if state.provisional {
+        remaining_slots.push_str(" • Restored data, waiting for aoe2lobby.com");
+    }
+
+    let mut details = format!(
+        "Description: {}\nRegion: {}\n",
+        state.description, state.relayserver_region
+    );
+    if let Some(map) = &state.map {
+        details.push_str(&format!("Map: {}\n", map));
+    }
+    if let Some(game_type) = &state.game_type {
+        details.push_str(&format!("Game type: {}\n", game_type));
+    }
+    if let Some(speed) = &state.speed {
+        details.push_str(&format!("Speed: {}\n", speed));
+    }
+    if let Some(spectators) = state.spectators {
+        details.push_str(&format!("Spectators: {}\n", spectators));
+    }
+
+    let title = if state.password_protected {
+        format!("aoe2de://0/{} 🔒", state.id)
+    } else {
+        format!("aoe2de://0/{}", state.id)
+    };
     embed
-        .title(format!("aoe2de://0/{}", state.id,))
+        .title(title)
         .url(format!("https://aoe2lobby.com/j/{}", state.id))
         .color(state.color)
         .footer(|footer| footer.text(remaining_slots))
-        .description(format!(
-            "Description: {}\nRegion: {}\n{}",
-            state.description,
-            state.relayserver_region,
-            state.players.clone()
-        ));
+        .description(format!("{}{}", details, state.players.clone()));
     embed
 }
 
-// fn create_components(state: &State) -> CreateComponents{
-//     let mut components = CreateComponents::default();
-//     components.set_action_row(create_action_row(state));
-//     components
-// }
-//
-// fn create_action_row(state: &State) -> CreateActionRow{
-//     let mut row = CreateActionRow::default();
-//     row.create_button(|button| {
-//         button
-//             .style(ButtonStyle::Success)
-//             .label("Join")
-//             .url(format!("https://aoe2lobby.com/j/{}", state.id))
-//     });
-//     row
-// }
+// Shown for lobbies that ended without the bot seeing how
+pub fn create_inactive_embed(lobby_id: &str) -> CreateEmbed {
+    let mut embed = CreateEmbed::default();
+    embed
+        .title(format!("aoe2de://0/{}", lobby_id))
+        .url(format!("https://aoe2lobby.com/j/{}", lobby_id))
+        .description("Lobby no longer active");
+    embed
+}
+
+// Replaces the live embed once the lobby is gone
+pub fn create_deleted_embed(lobby: &Lobby, reason: DeletionReason) -> CreateEmbed {
+    let description = match reason {
+        DeletionReason::Launched => format!(
+            "Game started with these {} players\n{}",
+            lobby.slotstaken,
+            format_players(lobby)
+        ),
+        DeletionReason::Abandoned => "Lobby abandoned".to_string(),
+        DeletionReason::Closed => "Lobby closed".to_string(),
+    };
+    let mut embed = CreateEmbed::default();
+    embed
+        .title(format!("aoe2de://0/{}", lobby.lobbyid))
+        .url(format!("https://aoe2lobby.com/j/{}", lobby.lobbyid))
+        .color(Colour::LIGHT_GREY)
+        .description(description);
+    embed
+}
+
+// Buttons shown below a lobby embed, only messages updated live can stop being tracked
+pub fn create_components(state: &State, live: bool) -> CreateComponents {
+    let mut row = CreateActionRow::default();
+    row.create_button(|button| {
+        button
+            .style(ButtonStyle::Link)
+            .label("Join")
+            .url(format!("https://aoe2lobby.com/j/{}", state.id))
+    })
+    .create_button(|button| {
+        button
+            .style(ButtonStyle::Secondary)
+            .label("Refresh")
+            .custom_id(format!("lobby:refresh:{}", state.id))
+    });
+    if live {
+        row.create_button(|button| {
+            button
+                .style(ButtonStyle::Danger)
+                .label("Stop tracking")
+                .custom_id(format!("lobby:stop:{}", state.id))
+        });
+    }
+    let mut components = CreateComponents::default();
+    components.set_action_row(row);
+    components
+}
 
 fn extract_colors(lobby: &Lobby) -> Color {
     if lobby.slotstotal - lobby.slotstaken > 0 {
@@ -399,7 +524,11 @@ fn format_players(lobby: &Lobby) -> String {
     let mut content = String::new();
     let mut players = vec![];
     for player in lobby.slot.values() {
-        players.push(player.name.clone().unwrap_or("Unknown".to_string()));
+        let name = player.name.clone().unwrap_or("Unknown".to_string());
+        match player.rating {
+            Some(rating) => players.push(format!("{} ({})", name, rating)),
+            None => players.push(name),
+        }
     }
     //Sort players by name
     players.sort();
@@ -1,3 +1,8 @@
 pub mod error;
+pub mod history;
 pub mod lobby;
+pub mod scheduler;
+pub mod search;
+pub mod tracked;
+pub mod tracker;
 pub mod util;
@@ -0,0 +1,212 @@
+use crate::commands::tracked::TrackedMessage;
+use crate::metrics;
+use serenity::builder::{CreateComponents, CreateEmbed};
+use serenity::client::Context;
+use serenity::http::HttpError;
+use serenity::model::id::ChannelId;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex as StdMutex};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, Instant};
+use tracing::{error, warn};
+use uuid::Uuid;
+
+// Longest wait between retries of a failing edit
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+// Discord rate limits interaction responses per token and messages per channel
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Bucket {
+    Interaction(String),
+    Channel(ChannelId),
+}
+
+impl From<&TrackedMessage> for Bucket {
+    fn from(message: &TrackedMessage) -> Self {
+        match message {
+            TrackedMessage::Interaction { token, .. } => Bucket::Interaction(token.clone()),
+            TrackedMessage::Channel { channel_id, .. } => Bucket::Channel(*channel_id),
+        }
+    }
+}
+
+// Spaces out the edits of live messages. Each message only keeps its latest pending embed, so a
+// lobby changing faster than edits go through just skips the intermediate states
+#[derive(Clone)]
+pub struct EditScheduler {
+    min_interval: Duration,
+    // Earliest time at which each bucket accepts its next edit
+    buckets: Arc<StdMutex<HashMap<Bucket, Instant>>>,
+}
+
+impl EditScheduler {
+    pub fn new(min_interval: Duration) -> Self {
+        Self {
+            min_interval,
+            buckets: Arc::new(StdMutex::new(HashMap::new())),
+        }
+    }
+
+    // Starts applying the embeds scheduled for the message. The uuid is sent to failures when the
+    // message can't be edited anymore
+    pub fn open(
+        &self,
+        ctx: &Context,
+        uuid: Uuid,
+        message: TrackedMessage,
+        failures: UnboundedSender<Uuid>,
+    ) -> ScheduledMessage {
+        let (embeds, receiver) = watch::channel(None);
+        let worker = EditWorker {
+            scheduler: self.clone(),
+            ctx: ctx.clone(),
+            uuid,
+            bucket: Bucket::from(&message),
+            message,
+            failures,
+        };
+        ScheduledMessage {
+            embeds,
+            worker: tokio::spawn(worker.run(receiver)),
+        }
+    }
+
+    // Waits for the next free slot of the bucket and takes it
+    async fn reserve(&self, bucket: &Bucket) {
+        let slot = {
+            let now = Instant::now();
+            let mut buckets = self.buckets.lock().unwrap();
+            buckets.retain(|_, next| *next > now);
+            let slot = buckets.get(bucket).map_or(now, |next| (*next).max(now));
+            buckets.insert(bucket.clone(), slot + self.min_interval);
+            slot
+        };
+        tokio::time::sleep_until(slot).await;
+    }
+
+    // Keeps every message of the bucket from being edited for a while
+    fn back_off(&self, bucket: &Bucket, delay: Duration) {
+        let until = Instant::now() + delay;
+        let mut buckets = self.buckets.lock().unwrap();
+        let next = buckets.entry(bucket.clone()).or_insert(until);
+        *next = (*next).max(until);
+    }
+}
+
+type Content = (CreateEmbed, CreateComponents);
+
+// A message edited through the scheduler. Dropping it still applies the pending embed
+pub struct ScheduledMessage {
+    embeds: watch::Sender<Option<Content>>,
+    worker: JoinHandle<()>,
+}
+
+impl ScheduledMessage {
+    // Replaces the pending embed, if any
+    pub fn edit(&self, embed: CreateEmbed, components: CreateComponents) {
+        self.embeds.send_replace(Some((embed, components)));
+    }
+
+    // Drops the pending embed and waits for an edit in flight, so the message can be edited
+    // directly afterwards. A worker waiting for its bucket or backing off stops right away
+    pub async fn discard(self) {
+        self.embeds.send_replace(None);
+        drop(self.embeds);
+        if let Err(e) = self.worker.await {
+            error!("Edit worker failed: {:?}", e);
+        }
+    }
+}
+
+struct EditWorker {
+    scheduler: EditScheduler,
+    ctx: Context,
+    uuid: Uuid,
+    bucket: Bucket,
+    message: TrackedMessage,
+    failures: UnboundedSender<Uuid>,
+}
+
+impl EditWorker {
+    async fn run(self, mut embeds: watch::Receiver<Option<Content>>) {
+        let mut attempt = 0;
+        loop {
+            // A retry goes out with whatever embed is the latest by then
+            if attempt == 0 && embeds.changed().await.is_err() {
+                return;
+            }
+            tokio::select! {
+                _ = self.scheduler.reserve(&self.bucket) => {}
+                _ = discarded(&mut embeds) => return,
+            }
+            let (embed, components) = match embeds.borrow_and_update().clone() {
+                Some(content) => content,
+                None => return,
+            };
+
+            match self.message.edit(&self.ctx, embed, components).await {
+                Ok(()) => attempt = 0,
+                // Once the message is dropped, its pending embed only gets one try
+                Err(why) if is_transient(&why) && embeds.has_changed().is_err() => {
+                    warn!(
+                        "Cannot edit a message that was dropped, giving up: {:?}",
+                        why
+                    );
+                    metrics::DISCORD_EDIT_FAILURES.inc();
+                    return;
+                }
+                Err(why) if is_transient(&why) => {
+                    attempt += 1;
+                    metrics::DISCORD_EDIT_RETRIES.inc();
+                    let delay = self
+                        .scheduler
+                        .min_interval
+                        .max(Duration::from_secs(1))
+                        .saturating_mul(1 << attempt.min(8))
+                        .min(MAX_BACKOFF);
+                    warn!(
+                        "Cannot edit message, retrying in {:?} (attempt {}): {:?}",
+                        delay, attempt, why
+                    );
+                    self.scheduler.back_off(&self.bucket, delay);
+                }
+                Err(why) => {
+                    error!("Cannot edit message: {:?}", why);
+                    metrics::DISCORD_EDIT_FAILURES.inc();
+                    self.failures.send(self.uuid).ok();
+                    return;
+                }
+            }
+        }
+    }
+}
+
+// Resolves once the pending embed is discarded. Newer embeds keep waiting, and so does the last
+// embed of a dropped message
+async fn discarded(embeds: &mut watch::Receiver<Option<Content>>) {
+    loop {
+        if embeds.borrow().is_none() {
+            return;
+        }
+        if embeds.changed().await.is_err() {
+            return futures::future::pending().await;
+        }
+    }
+}
+
+// Rate limits, Discord outages and network errors go away on their own, anything else means the
+// message can't be edited anymore
+fn is_transient(error: &serenity::Error) -> bool {
+    match error {
+        serenity::Error::Http(error) => match error.as_ref() {
+            HttpError::UnsuccessfulRequest(response) => {
+                response.status_code.as_u16() == 429 || response.status_code.is_server_error()
+            }
+            HttpError::Request(_) => true,
+            _ => false,
+        },
+        _ => false,
+    }
+}
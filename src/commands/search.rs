@@ -0,0 +1,140 @@
+use crate::commands::lobby::unavailable_message;
+use crate::commands::util::{create_embed_response, create_interaction_response, escape_markdown};
+use crate::lobby_cache::index::{tokenize, LobbyQuery};
+use crate::lobby_cache::model::Lobby;
+use crate::lobby_cache::store::LobbyStore;
+use serenity::builder::CreateApplicationCommand;
+use serenity::client::Context;
+use serenity::model::application::interaction::application_command::{
+    ApplicationCommandInteraction, CommandDataOption,
+};
+use serenity::model::prelude::command::CommandOptionType;
+use std::sync::Arc;
+use tracing::log::debug;
+
+const MAX_RESULTS: usize = 10;
+const MAX_DESCRIPTION_LENGTH: usize = 40;
+
+pub struct SearchHandler {
+    lobby_store: Arc<dyn LobbyStore>,
+}
+
+impl SearchHandler {
+    pub fn new(lobby_store: Arc<dyn LobbyStore>) -> Self {
+        Self { lobby_store }
+    }
+
+    pub async fn run(&self, ctx: &Context, command: ApplicationCommandInteraction) {
+        let query = match extract_query(&command.data.options) {
+            Ok(query) => query,
+            Err(message) => {
+                create_interaction_response(ctx, command, message).await;
+                return;
+            }
+        };
+        debug!("Search query: {:?}", query);
+        if query == LobbyQuery::default() {
+            create_interaction_response(ctx, command, "Specify at least one filter".to_string())
+                .await;
+            return;
+        }
+        let connection_state = self.lobby_store.connection_state();
+        if !connection_state.is_serving() || !self.lobby_store.is_fresh().await {
+            create_interaction_response(ctx, command, unavailable_message(&connection_state)).await;
+            return;
+        }
+
+        let mut lobbies = self.lobby_store.search(&query);
+        if lobbies.is_empty() {
+            create_interaction_response(ctx, command, "No matching lobbies".to_string()).await;
+            return;
+        }
+        // Lobbies that are easiest to join first
+        lobbies.sort_by_key(|lobby| (lobby.slotstaken - lobby.slotstotal, lobby.lobbyid));
+
+        let mut lines: Vec<String> = lobbies
+            .iter()
+            .take(MAX_RESULTS)
+            .map(|lobby| format_result(lobby))
+            .collect();
+        if lobbies.len() > MAX_RESULTS {
+            lines.push(format!("...and {} more", lobbies.len() - MAX_RESULTS));
+        }
+        create_embed_response(ctx, command, "Matching lobbies", lines).await;
+    }
+
+    pub fn register(command: &mut CreateApplicationCommand) -> &mut CreateApplicationCommand {
+        command
+            .name("search")
+            .description("Search active Aoe2 lobbies")
+            .create_option(|option| {
+                option
+                    .name("player")
+                    .description("Name of a player in the lobby")
+                    .kind(CommandOptionType::String)
+            })
+            .create_option(|option| {
+                option
+                    .name("region")
+                    .description("Relay server region(ex: westeurope)")
+                    .kind(CommandOptionType::String)
+            })
+            .create_option(|option| {
+                option
+                    .name("open_slots")
+                    .description("Minimum number of open slots")
+                    .kind(CommandOptionType::Integer)
+                    .min_int_value(1)
+            })
+            .create_option(|option| {
+                option
+                    .name("text")
+                    .description("Words in the lobby description")
+                    .kind(CommandOptionType::String)
+            })
+    }
+}
+
+fn extract_query(options: &[CommandDataOption]) -> Result<LobbyQuery, &'static str> {
+    let mut query = LobbyQuery::default();
+    for option in options {
+        let value = match &option.value {
+            Some(value) => value,
+            None => continue,
+        };
+        match option.name.as_str() {
+            "player" => query.player = value.as_str().map(|player| player.trim().to_string()),
+            "region" => query.region = value.as_str().map(|region| region.trim().to_string()),
+            "open_slots" => query.min_open_slots = value.as_i64(),
+            "text" => {
+                let text = value.as_str().unwrap_or_default();
+                // Would otherwise match every lobby
+                if tokenize(text).is_empty() {
+                    return Err("The text filter must contain at least one word");
+                }
+                query.words = vec![text.to_string()];
+            }
+            _ => {}
+        }
+    }
+    Ok(query)
+}
+
+fn format_result(lobby: &Lobby) -> String {
+    let mut description: String = lobby
+        .description
+        .chars()
+        .take(MAX_DESCRIPTION_LENGTH)
+        .collect();
+    if description.len() < lobby.description.len() {
+        description.push('…');
+    }
+    format!(
+        "aoe2de://0/{} • {} • {} • {}/{}",
+        lobby.lobbyid,
+        escape_markdown(&description),
+        escape_markdown(&lobby.relayserver_region),
+        lobby.slotstaken,
+        lobby.slotstotal
+    )
+}
@@ -0,0 +1,247 @@
+use crate::lobby_cache::util::write_atomically;
+use serde_derive::{Deserialize, Serialize};
+use serenity::builder::{CreateComponents, CreateEmbed, EditInteractionResponse};
+use serenity::client::Context;
+use serenity::json::{self, Value};
+use serenity::model::channel::Message;
+use serenity::model::id::{ChannelId, InteractionId, MessageId, UserId};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::SystemTime;
+use tracing::error;
+use uuid::Uuid;
+
+// Where a tracked lobby is shown. Interaction responses can only be edited for 15 minutes, after
+// that the lobby is shown in a regular channel message
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum TrackedMessage {
+    // Edited through the interaction token, which outlives a restart but not the 15 minutes
+    Interaction {
+        token: String,
+        channel_id: ChannelId,
+        // Matches button clicks to the response, missing for responses tracked before buttons
+        #[serde(default)]
+        interaction_id: Option<InteractionId>,
+    },
+    Channel {
+        channel_id: ChannelId,
+        message_id: MessageId,
+    },
+}
+
+impl TrackedMessage {
+    // Whether the message stops being editable after the interaction deadline
+    pub fn expires(&self) -> bool {
+        matches!(self, TrackedMessage::Interaction { .. })
+    }
+
+    // Whether a button was clicked on this message
+    pub fn is(&self, message: &Message) -> bool {
+        match self {
+            TrackedMessage::Interaction { interaction_id, .. } => {
+                matches!(
+                    (interaction_id, &message.interaction),
+                    (Some(id), Some(interaction)) if interaction.id == *id
+                )
+            }
+            TrackedMessage::Channel { message_id, .. } => *message_id == message.id,
+        }
+    }
+
+    // Empty components remove the buttons
+    pub async fn edit(
+        &self,
+        ctx: &Context,
+        embed: CreateEmbed,
+        components: CreateComponents,
+    ) -> serenity::Result<()> {
+        match self {
+            TrackedMessage::Interaction { token, .. } => {
+                let mut response = EditInteractionResponse::default();
+                response.set_embed(embed).components(|c| {
+                    *c = components;
+                    c
+                });
+                let map = json::hashmap_to_json_map(response.0);
+                ctx.http
+                    .edit_original_interaction_response(token, &Value::from(map))
+                    .await
+                    .map(|_| ())
+            }
+            TrackedMessage::Channel {
+                channel_id,
+                message_id,
+            } => channel_id
+                .edit_message(&ctx.http, *message_id, |message| {
+                    message.set_embed(embed).set_components(components)
+                })
+                .await
+                .map(|_| ()),
+        }
+    }
+
+    // Sends the embed as a channel message and keeps updating that one instead of the interaction
+    // response, which is left pointing to it
+    pub async fn hand_off(
+        &mut self,
+        ctx: &Context,
+        lobby_id: &str,
+        embed: CreateEmbed,
+        components: CreateComponents,
+    ) -> serenity::Result<()> {
+        let channel_id = match self {
+            TrackedMessage::Interaction { channel_id, .. } => *channel_id,
+            TrackedMessage::Channel { .. } => return Ok(()),
+        };
+        let message = channel_id
+            .send_message(&ctx.http, |message| {
+                message.set_embed(embed).set_components(components)
+            })
+            .await?;
+
+        let mut moved_embed = CreateEmbed::default();
+        moved_embed
+            .title(lobby_id)
+            .description(format!("Now updated live in {}", message.link()));
+        // Fails when the token already expired, e.g. when tracking resumes after a long restart
+        if let Err(why) = self
+            .edit(ctx, moved_embed, CreateComponents::default())
+            .await
+        {
+            error!(
+                "Cannot point the interaction response to the new message: {:?}",
+                why
+            );
+        }
+
+        *self = TrackedMessage::Channel {
+            channel_id: message.channel_id,
+            message_id: message.id,
+        };
+        Ok(())
+    }
+}
+
+// A lobby embed kept up to date, persisted so tracking resumes after a restart
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TrackedLobby {
+    // The numeric lobby id, without the aoe2de://0/ prefix
+    pub lobby_id: String,
+    pub requester: UserId,
+    pub started_at: SystemTime,
+    pub message: TrackedMessage,
+}
+
+// Tracked lobbies, mirrored to a json file when a path is configured
+pub struct TrackedStore {
+    path: Option<PathBuf>,
+    tracked: StdMutex<HashMap<Uuid, TrackedLobby>>,
+    // Bumped on every change, while the tracked lobbies are locked
+    version: AtomicU64,
+    // The version last written to the file, locked for the whole write
+    written: Arc<StdMutex<u64>>,
+}
+
+impl TrackedStore {
+    // Starts empty when the file doesn't exist yet or can't be read
+    pub fn open(path: Option<PathBuf>) -> Self {
+        let tracked = match &path {
+            Some(path) => match read_tracked(path) {
+                Ok(tracked) => tracked,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+                Err(e) => {
+                    error!("Failed to read tracked lobbies {}: {:?}", path.display(), e);
+                    vec![]
+                }
+            },
+            None => vec![],
+        };
+        Self {
+            path,
+            tracked: StdMutex::new(
+                tracked
+                    .into_iter()
+                    .map(|tracked| (Uuid::new_v4(), tracked))
+                    .collect(),
+            ),
+            version: AtomicU64::new(0),
+            written: Arc::new(StdMutex::new(0)),
+        }
+    }
+
+    // Every tracked lobby with the id it is stored under, in the order they were started
+    pub fn list(&self) -> Vec<(Uuid, TrackedLobby)> {
+        let mut tracked_lobbies: Vec<_> = self
+            .tracked
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(uuid, tracked)| (*uuid, tracked.clone()))
+            .collect();
+        tracked_lobbies.sort_by_key(|(_, tracked)| tracked.started_at);
+        tracked_lobbies
+    }
+
+    pub fn insert(&self, uuid: Uuid, tracked: TrackedLobby) {
+        let mut tracked_lobbies = self.tracked.lock().unwrap();
+        tracked_lobbies.insert(uuid, tracked);
+        self.persist(&tracked_lobbies);
+    }
+
+    pub fn set_message(&self, uuid: Uuid, message: &TrackedMessage) {
+        let mut tracked_lobbies = self.tracked.lock().unwrap();
+        if let Some(tracked) = tracked_lobbies.get_mut(&uuid) {
+            tracked.message = message.clone();
+            self.persist(&tracked_lobbies);
+        }
+    }
+
+    pub fn remove(&self, uuid: Uuid) {
+        let mut tracked_lobbies = self.tracked.lock().unwrap();
+        if tracked_lobbies.remove(&uuid).is_some() {
+            self.persist(&tracked_lobbies);
+        }
+    }
+
+    // The file is written on the blocking pool, writes finishing out of order never replace a
+    // newer content with an older one
+    fn persist(&self, tracked_lobbies: &HashMap<Uuid, TrackedLobby>) {
+        let path = match &self.path {
+            Some(path) => path.clone(),
+            None => return,
+        };
+        let tracked: Vec<&TrackedLobby> = tracked_lobbies.values().collect();
+        let content = match serde_json::to_vec(&tracked) {
+            Ok(content) => content,
+            Err(e) => {
+                error!("Failed to serialize tracked lobbies: {:?}", e);
+                return;
+            }
+        };
+        let version = self.version.fetch_add(1, Ordering::Relaxed) + 1;
+        let written = self.written.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut written = written.lock().unwrap();
+            if *written > version {
+                return;
+            }
+            match write_atomically(&path, &content) {
+                Ok(()) => *written = version,
+                Err(e) => error!(
+                    "Failed to write tracked lobbies {}: {:?}",
+                    path.display(),
+                    e
+                ),
+            }
+        });
+    }
+}
+
+fn read_tracked(path: &Path) -> io::Result<Vec<TrackedLobby>> {
+    Ok(serde_json::from_slice(&fs::read(path)?)?)
+}
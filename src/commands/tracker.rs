@@ -0,0 +1,489 @@
+use crate::commands::error;
+use crate::commands::lobby::{
+    create_components, create_deleted_embed, create_embed, create_inactive_embed, extract_state,
+    State,
+};
+use crate::commands::scheduler::{EditScheduler, ScheduledMessage};
+use crate::commands::tracked::{TrackedLobby, TrackedStore};
+use crate::lobby_cache::store::{LobbyStore, WatchedLobby};
+use crate::metrics;
+use serenity::builder::{CreateComponents, CreateEmbed};
+use serenity::client::Context;
+use serenity::model::channel::Message;
+use serenity::model::id::UserId;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{oneshot, watch, Mutex};
+use tokio::time::{Duration, Instant};
+use tracing::log::debug;
+use tracing::{error, info};
+use uuid::Uuid;
+
+// Lobbies tracked at once, across every channel
+const MAX_LOBBIES: usize = 6;
+// Messages attached to the same lobby, the oldest one stops being updated past that
+const MAX_ATTACHMENTS: usize = 4;
+
+type Trackers = Arc<Mutex<HashMap<String, UnboundedSender<TrackerCommand>>>>;
+
+enum TrackerCommand {
+    Attach(Attachment),
+    // Answered once the button is handled
+    Button(ButtonPress, oneshot::Sender<ButtonOutcome>),
+}
+
+pub enum ButtonAction {
+    Refresh,
+    // Moderators can stop any message, others only the ones they requested
+    Stop { moderator: bool },
+}
+
+struct ButtonPress {
+    action: ButtonAction,
+    message: Box<Message>,
+    user: UserId,
+}
+
+pub enum ButtonOutcome {
+    Done,
+    NotAllowed,
+    // The message isn't updated live, e.g. the lobby ended or tracking stopped
+    NotTracked,
+}
+
+// One task per tracked lobby, diffing its state once and editing every message attached to it
+pub struct LobbyTrackers {
+    lobby_store: Arc<dyn LobbyStore>,
+    tracked_store: Arc<TrackedStore>,
+    scheduler: EditScheduler,
+    trackers: Trackers,
+}
+
+// A message showing the tracked lobby
+struct Attachment {
+    uuid: Uuid,
+    tracked: TrackedLobby,
+    // When the interaction response stops being editable
+    deadline: Instant,
+}
+
+impl Attachment {
+    fn new(uuid: Uuid, tracked: TrackedLobby) -> Self {
+        // Discord allows for up to 15 minutes for a response, tracking then moves to a channel
+        // message
+        let deadline = Instant::now()
+            + Duration::from_secs(14 * 60)
+                .saturating_sub(tracked.started_at.elapsed().unwrap_or_default());
+        Self {
+            uuid,
+            tracked,
+            deadline,
+        }
+    }
+}
+
+impl LobbyTrackers {
+    pub fn new(
+        lobby_store: Arc<dyn LobbyStore>,
+        tracked_store: TrackedStore,
+        scheduler: EditScheduler,
+    ) -> Self {
+        Self {
+            lobby_store,
+            tracked_store: Arc::new(tracked_store),
+            scheduler,
+            trackers: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn tracked_store(&self) -> &TrackedStore {
+        &self.tracked_store
+    }
+
+    // Whether a message could be attached to the lobby right now
+    pub async fn has_room(&self, lobby_id: &str) -> bool {
+        let trackers = self.trackers.lock().await;
+        trackers.contains_key(lobby_id) || trackers.len() < MAX_LOBBIES
+    }
+
+    // Keeps the message in sync with the lobby until the lobby ends, or newer messages for the
+    // same lobby take over
+    pub async fn attach(
+        &self,
+        ctx: &Context,
+        uuid: Uuid,
+        tracked: TrackedLobby,
+    ) -> error::Result<()> {
+        let mut trackers = self.trackers.lock().await;
+        let attachment = Attachment::new(uuid, tracked);
+        if let Some(sender) = trackers.get(&attachment.tracked.lobby_id) {
+            // Only closed when the tracker task panicked
+            if !sender.is_closed() {
+                sender.send(TrackerCommand::Attach(attachment)).ok();
+                return Ok(());
+            }
+            error!("Tracker of lobby {} is gone", attachment.tracked.lobby_id);
+            trackers.remove(&attachment.tracked.lobby_id);
+        }
+        if trackers.len() >= MAX_LOBBIES {
+            return Err(error::CommandError::TooManyLobbies);
+        }
+
+        let lobby_id = attachment.tracked.lobby_id.clone();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        sender.send(TrackerCommand::Attach(attachment)).ok();
+        trackers.insert(lobby_id.clone(), sender);
+
+        let (failures, failure_receiver) = mpsc::unbounded_channel();
+        let tracker = Tracker {
+            ctx: ctx.clone(),
+            lobby_watch: self.lobby_store.watch(&lobby_id),
+            lobby_id,
+            lobby_store: self.lobby_store.clone(),
+            tracked_store: self.tracked_store.clone(),
+            scheduler: self.scheduler.clone(),
+            trackers: self.trackers.clone(),
+            failures,
+            attachments: vec![],
+        };
+        tokio::spawn(tracker.run(receiver, failure_receiver));
+        Ok(())
+    }
+
+    // Hands a button clicked on a lobby message to the tracker of the lobby
+    pub async fn press(
+        &self,
+        lobby_id: &str,
+        action: ButtonAction,
+        message: Message,
+        user: UserId,
+    ) -> ButtonOutcome {
+        let (reply, outcome) = oneshot::channel();
+        {
+            let trackers = self.trackers.lock().await;
+            let sender = match trackers.get(lobby_id) {
+                Some(sender) => sender,
+                None => return ButtonOutcome::NotTracked,
+            };
+            let press = ButtonPress {
+                action,
+                message: Box::new(message),
+                user,
+            };
+            if sender.send(TrackerCommand::Button(press, reply)).is_err() {
+                return ButtonOutcome::NotTracked;
+            }
+        }
+        outcome.await.unwrap_or(ButtonOutcome::NotTracked)
+    }
+}
+
+struct Tracker {
+    ctx: Context,
+    lobby_id: String,
+    lobby_watch: watch::Receiver<WatchedLobby>,
+    lobby_store: Arc<dyn LobbyStore>,
+    tracked_store: Arc<TrackedStore>,
+    scheduler: EditScheduler,
+    trackers: Trackers,
+    // Edit workers report the messages they can't edit anymore here
+    failures: UnboundedSender<Uuid>,
+    // Newest first
+    attachments: Vec<Attached>,
+}
+
+// An attachment whose message is being edited through the scheduler
+struct Attached {
+    attachment: Attachment,
+    scheduled: ScheduledMessage,
+}
+
+impl Tracker {
+    async fn run(
+        mut self,
+        mut receiver: UnboundedReceiver<TrackerCommand>,
+        mut failures: UnboundedReceiver<Uuid>,
+    ) {
+        info!("Tracking lobby {}", self.lobby_id);
+        let lobby = self.lobby_watch.borrow_and_update().clone();
+        let mut state = match lobby {
+            WatchedLobby::Active(lobby) => extract_state(&lobby, self.lobby_store.is_provisional()),
+            WatchedLobby::Deleted { lobby, reason } => {
+                self.finish(receiver, create_deleted_embed(&lobby, reason))
+                    .await;
+                return;
+            }
+            // Only happens when resuming, the lobby ended while the bot was down
+            WatchedLobby::Missing => {
+                let embed = create_inactive_embed(&self.lobby_id);
+                self.finish(receiver, embed).await;
+                return;
+            }
+        };
+
+        loop {
+            if self.attachments.is_empty() {
+                // Attaching happens under the same lock, so nothing can be sent to the tracker
+                // once it's unregistered
+                let mut trackers = self.trackers.lock().await;
+                match receiver.try_recv() {
+                    Ok(command) => {
+                        drop(trackers);
+                        self.handle(command, &state);
+                    }
+                    Err(_) => {
+                        trackers.remove(&self.lobby_id);
+                        info!("Stopped tracking lobby {}", self.lobby_id);
+                        return;
+                    }
+                }
+                continue;
+            }
+
+            let next_deadline = self
+                .attachments
+                .iter()
+                .filter(|attached| attached.attachment.tracked.message.expires())
+                .map(|attached| attached.attachment.deadline)
+                .min();
+            let deadline = next_deadline.unwrap_or_else(Instant::now);
+
+            debug!("Inside of updater loop");
+            tokio::select! {
+                Some(command) = receiver.recv() => {
+                    self.handle(command, &state);
+                }
+                Some(uuid) = failures.recv() => {
+                    if let Some(index) = self
+                        .attachments
+                        .iter()
+                        .position(|attached| attached.attachment.uuid == uuid)
+                    {
+                        debug!("Detaching a message that can't be edited anymore");
+                        let attached = self.attachments.remove(index);
+                        self.detach(attached);
+                    }
+                }
+                _ = tokio::time::sleep_until(deadline), if next_deadline.is_some() => {
+                    self.hand_off_expired(&state).await;
+                }
+                changed = self.lobby_watch.changed() => {
+                    if changed.is_err() {
+                        error!("Lobby watch channel closed");
+                        let mut last_embed = create_embed(&state);
+                        last_embed.footer(|footer| footer.text("Message no longer updated live"));
+                        self.finish(receiver, last_embed).await;
+                        return;
+                    }
+                    debug!("Received update");
+
+                    let lobby = self.lobby_watch.borrow_and_update().clone();
+                    match lobby {
+                        // Watchers don't go back to missing once the lobby was seen
+                        WatchedLobby::Missing => {}
+
+                        WatchedLobby::Deleted { lobby, reason } => {
+                            debug!("Lobby no longer running: {}", reason);
+                            self.finish(receiver, create_deleted_embed(&lobby, reason)).await;
+                            return;
+                        }
+
+                        WatchedLobby::Active(lobby) => {
+                            let new_state = extract_state(&lobby, self.lobby_store.is_provisional());
+                            if new_state == state {
+                                debug!("No change in state");
+                                continue;
+                            }
+                            debug!("Change in players");
+                            state = new_state;
+                            let embed = create_embed(&state);
+                            let components = create_components(&state, true);
+                            for attached in &self.attachments {
+                                attached.scheduled.edit(embed.clone(), components.clone());
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn handle(&mut self, command: TrackerCommand, state: &State) {
+        match command {
+            TrackerCommand::Attach(attachment) => self.attach(attachment, state),
+            TrackerCommand::Button(press, reply) => {
+                reply.send(self.press(press, state)).ok();
+            }
+        }
+    }
+
+    fn press(&mut self, press: ButtonPress, state: &State) -> ButtonOutcome {
+        let index = match self
+            .attachments
+            .iter()
+            .position(|attached| attached.attachment.tracked.message.is(&press.message))
+        {
+            Some(index) => index,
+            None => return ButtonOutcome::NotTracked,
+        };
+        match press.action {
+            // Re-sends the current state, in case an edit got lost
+            ButtonAction::Refresh => {
+                self.attachments[index]
+                    .scheduled
+                    .edit(create_embed(state), create_components(state, true));
+            }
+            ButtonAction::Stop { moderator } => {
+                if self.attachments[index].attachment.tracked.requester != press.user && !moderator
+                {
+                    return ButtonOutcome::NotAllowed;
+                }
+                debug!("Stopped tracking a message of lobby {}", self.lobby_id);
+                let attached = self.attachments.remove(index);
+                let mut last_embed = create_embed(state);
+                last_embed.footer(|footer| footer.text("Message no longer updated live"));
+                attached
+                    .scheduled
+                    .edit(last_embed, CreateComponents::default());
+                self.detach(attached);
+            }
+        }
+        ButtonOutcome::Done
+    }
+
+    fn schedule(&self, attachment: &Attachment) -> ScheduledMessage {
+        self.scheduler.open(
+            &self.ctx,
+            attachment.uuid,
+            attachment.tracked.message.clone(),
+            self.failures.clone(),
+        )
+    }
+
+    // Shows the current state in a new message, replacing the oldest one when the lobby already
+    // has too many
+    fn attach(&mut self, attachment: Attachment, state: &State) {
+        if self.attachments.len() >= MAX_ATTACHMENTS {
+            let oldest = self.attachments.pop().unwrap();
+            debug!("Detaching the oldest message of lobby {}", self.lobby_id);
+            let mut last_embed = create_embed(state);
+            last_embed.footer(|footer| footer.text("Message no longer updated live"));
+            oldest
+                .scheduled
+                .edit(last_embed, CreateComponents::default());
+            self.detach(oldest);
+        }
+
+        let scheduled = self.schedule(&attachment);
+        scheduled.edit(create_embed(state), create_components(state, true));
+        self.tracked_store
+            .insert(attachment.uuid, attachment.tracked.clone());
+        self.attachments.insert(
+            0,
+            Attached {
+                attachment,
+                scheduled,
+            },
+        );
+        metrics::TRACKED_INTERACTIONS.inc();
+    }
+
+    // The last scheduled edit still goes through
+    fn detach(&self, attached: Attached) {
+        self.tracked_store.remove(attached.attachment.uuid);
+        metrics::TRACKED_INTERACTIONS.dec();
+    }
+
+    async fn hand_off_expired(&mut self, state: &State) {
+        let lobby_id = format!("aoe2de://0/{}", self.lobby_id);
+        let now = Instant::now();
+        let mut attachments = Vec::with_capacity(self.attachments.len());
+        for attached in std::mem::take(&mut self.attachments) {
+            let Attached {
+                mut attachment,
+                scheduled,
+            } = attached;
+            if !attachment.tracked.message.expires() || attachment.deadline > now {
+                attachments.push(Attached {
+                    attachment,
+                    scheduled,
+                });
+                continue;
+            }
+            // A pending edit could otherwise overwrite the link to the channel message
+            scheduled.discard().await;
+            let result = attachment
+                .tracked
+                .message
+                .hand_off(
+                    &self.ctx,
+                    &lobby_id,
+                    create_embed(state),
+                    create_components(state, true),
+                )
+                .await;
+            let scheduled = self.schedule(&attachment);
+            let attached = Attached {
+                attachment,
+                scheduled,
+            };
+            match result {
+                Ok(()) => {
+                    debug!("Moved tracking to a channel message");
+                    self.tracked_store.set_message(
+                        attached.attachment.uuid,
+                        &attached.attachment.tracked.message,
+                    );
+                    attachments.push(attached);
+                }
+                Err(why) => {
+                    error!("Cannot move tracking to a channel message: {:?}", why);
+                    metrics::DISCORD_EDIT_FAILURES.inc();
+                    let mut last_embed = create_embed(state);
+                    last_embed.footer(|footer| {
+                        footer.text(
+                            "Message no longer updated live, as it was up for over 15 minutes",
+                        )
+                    });
+                    attached
+                        .scheduled
+                        .edit(last_embed, CreateComponents::default());
+                    self.detach(attached);
+                }
+            }
+        }
+        self.attachments = attachments;
+    }
+
+    // Shows the final embed in every message, including those attached while the tracker was
+    // stopping
+    async fn finish(mut self, mut receiver: UnboundedReceiver<TrackerCommand>, embed: CreateEmbed) {
+        let mut pending = vec![];
+        {
+            let mut trackers = self.trackers.lock().await;
+            trackers.remove(&self.lobby_id);
+            while let Ok(command) = receiver.try_recv() {
+                match command {
+                    TrackerCommand::Attach(attachment) => pending.push(attachment),
+                    TrackerCommand::Button(_, reply) => {
+                        reply.send(ButtonOutcome::NotTracked).ok();
+                    }
+                }
+            }
+        }
+
+        for attached in std::mem::take(&mut self.attachments) {
+            attached
+                .scheduled
+                .edit(embed.clone(), CreateComponents::default());
+            self.detach(attached);
+        }
+        for attachment in pending {
+            self.schedule(&attachment)
+                .edit(embed.clone(), CreateComponents::default());
+            self.tracked_store.remove(attachment.uuid);
+        }
+        info!("Stopped tracking lobby {}", self.lobby_id);
+    }
+}
@@ -1,8 +1,12 @@
+use crate::metrics;
 use serenity::client::Context;
 use serenity::model::application::interaction::application_command::ApplicationCommandInteraction;
 use serenity::model::application::interaction::InteractionResponseType;
 use tracing::error;
 
+// Discord rejects embed descriptions longer than this
+const MAX_EMBED_DESCRIPTION_LENGTH: usize = 4096;
+
 pub async fn create_interaction_response<D: ToString>(
     ctx: &Context,
     command: ApplicationCommandInteraction,
@@ -17,5 +21,92 @@ pub async fn create_interaction_response<D: ToString>(
         .await
     {
         error!("Cannot respond to slash command: {:?}", why);
+        metrics::DISCORD_EDIT_FAILURES.inc();
+    }
+}
+
+// Responds with lines that may hold user controlled text, in an embed that never pings anyone.
+// Lines that don't fit are left out
+pub async fn create_embed_response(
+    ctx: &Context,
+    command: ApplicationCommandInteraction,
+    title: &str,
+    lines: Vec<String>,
+) {
+    let description = fit_lines(lines, MAX_EMBED_DESCRIPTION_LENGTH);
+    if let Err(why) = command
+        .create_interaction_response(&ctx.http, |response| {
+            response
+                .kind(InteractionResponseType::ChannelMessageWithSource)
+                .interaction_response_data(|message| {
+                    message
+                        .embed(|embed| embed.title(title).description(description))
+                        .allowed_mentions(|mentions| mentions.empty_parse())
+                })
+        })
+        .await
+    {
+        error!("Cannot respond to slash command: {:?}", why);
+        metrics::DISCORD_EDIT_FAILURES.inc();
+    }
+}
+
+fn fit_lines(lines: Vec<String>, max_length: usize) -> String {
+    let omitted = "…";
+    let mut content = String::new();
+    let mut length = 0;
+    for line in lines {
+        let line_length = line.chars().count() + 1;
+        if length + line_length + omitted.len() > max_length {
+            content.push_str(omitted);
+            break;
+        }
+        content.push_str(&line);
+        content.push('\n');
+        length += line_length;
+    }
+    content
+}
+
+// Keeps user controlled text from being rendered as markdown
+pub fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(
+            c,
+            '\\' | '*' | '_' | '~' | '`' | '|' | '>' | '#' | '[' | ']'
+        ) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fits_every_line_under_the_limit() {
+        let lines = vec!["first".to_string(), "second".to_string()];
+        assert_eq!(fit_lines(lines, 100), "first\nsecond\n");
+    }
+
+    #[test]
+    fn leaves_out_lines_past_the_limit() {
+        let lines: Vec<String> = (0..10).map(|_| "x".repeat(9)).collect();
+        let content = fit_lines(lines, 35);
+        assert_eq!(content, format!("{}…", "xxxxxxxxx\n".repeat(3)));
+        assert!(content.chars().count() <= 35);
+    }
+
+    #[test]
+    fn escapes_markdown_characters() {
+        assert_eq!(
+            escape_markdown("*bold* _it_ `code` [link](url) > quote \\"),
+            "\\*bold\\* \\_it\\_ \\`code\\` \\[link\\](url) \\> quote \\\\"
+        );
+        assert_eq!(escape_markdown("plain text"), "plain text");
     }
 }
@@ -0,0 +1,102 @@
+use crate::lobby_cache::reconnect::CircuitState;
+use crate::lobby_cache::state::ConnectionState;
+use crate::lobby_cache::store::LobbyStore;
+use crate::lobby_cache::LobbyCache;
+use crate::metrics;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serenity::client::bridge::gateway::{ShardId, ShardRunnerInfo};
+use serenity::gateway::ConnectionStage;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::{error, info};
+
+pub type ShardRunners = Arc<Mutex<HashMap<ShardId, ShardRunnerInfo>>>;
+
+pub struct HealthState {
+    lobby_cache: Arc<LobbyCache>,
+    shard_runners: ShardRunners,
+}
+
+impl HealthState {
+    pub fn new(lobby_cache: Arc<LobbyCache>, shard_runners: ShardRunners) -> Self {
+        Self {
+            lobby_cache,
+            shard_runners,
+        }
+    }
+
+    // Returns the reasons the bot can't serve commands, empty when it is ready
+    async fn unready_reasons(&self) -> Vec<String> {
+        let mut reasons = vec![];
+        let connection_state = self.lobby_cache.connection_state();
+        if !connection_state.is_serving() {
+            reasons.push(format!("lobby cache is {}", connection_state));
+        }
+        if !self.lobby_cache.is_fresh().await {
+            reasons.push("lobby cache is stale".to_string());
+        }
+        if let ConnectionState::Disconnected(status) = &connection_state {
+            if status.circuit == CircuitState::Open {
+                reasons.push("lobby websocket circuit breaker is open".to_string());
+            }
+        }
+        let discord_connected = self
+            .shard_runners
+            .lock()
+            .await
+            .values()
+            .any(|runner| runner.stage == ConnectionStage::Connected);
+        if !discord_connected {
+            reasons.push("discord shard is not connected".to_string());
+        }
+        reasons
+    }
+}
+
+async fn handle(state: Arc<HealthState>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let response = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/healthz") => text_response(StatusCode::OK, "ok".to_string()),
+        (&Method::GET, "/readyz") => {
+            let reasons = state.unready_reasons().await;
+            if reasons.is_empty() {
+                text_response(StatusCode::OK, "ok".to_string())
+            } else {
+                text_response(StatusCode::SERVICE_UNAVAILABLE, reasons.join("\n"))
+            }
+        }
+        (&Method::GET, "/metrics") => {
+            text_response(StatusCode::OK, metrics::render(&state.lobby_cache).await)
+        }
+        _ => text_response(StatusCode::NOT_FOUND, "not found".to_string()),
+    };
+    Ok(response)
+}
+
+fn text_response(status: StatusCode, body: String) -> Response<Body> {
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    response
+}
+
+pub async fn serve(addr: SocketAddr, state: Arc<HealthState>) {
+    let make_service = make_service_fn(move |_| {
+        let state = state.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| handle(state.clone(), req))) }
+    });
+
+    let server = match Server::try_bind(&addr) {
+        Ok(server) => server.serve(make_service),
+        Err(e) => {
+            error!("Failed to bind health server to {}: {:?}", addr, e);
+            return;
+        }
+    };
+    info!("Health server listening on {}", addr);
+    if let Err(e) = server.await {
+        error!("Health server error: {:?}", e);
+    }
+}
@@ -0,0 +1,99 @@
+use crate::lobby_cache::util::unix_millis;
+use serde_derive::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex as StdMutex;
+use std::time::SystemTime;
+
+use tokio_tungstenite::tungstenite::Message;
+use tracing::error;
+
+// One line of a capture file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CapturedFrame {
+    // Milliseconds since the unix epoch at which the frame was received
+    pub timestamp: u64,
+    pub message: CapturedMessage,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CapturedMessage {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close,
+}
+
+impl CapturedMessage {
+    fn from_message(message: &Message) -> Option<Self> {
+        match message {
+            Message::Text(text) => Some(CapturedMessage::Text(text.clone())),
+            Message::Binary(data) => Some(CapturedMessage::Binary(data.clone())),
+            Message::Ping(data) => Some(CapturedMessage::Ping(data.clone())),
+            Message::Pong(data) => Some(CapturedMessage::Pong(data.clone())),
+            Message::Close(_) => Some(CapturedMessage::Close),
+            // Raw frames are never returned when reading
+            Message::Frame(_) => None,
+        }
+    }
+
+    pub fn into_message(self) -> Message {
+        match self {
+            CapturedMessage::Text(text) => Message::Text(text),
+            CapturedMessage::Binary(data) => Message::Binary(data),
+            CapturedMessage::Ping(data) => Message::Ping(data),
+            CapturedMessage::Pong(data) => Message::Pong(data),
+            CapturedMessage::Close => Message::Close(None),
+        }
+    }
+}
+
+// Appends every received websocket message to a JSONL file
+pub struct CaptureWriter {
+    writer: StdMutex<BufWriter<File>>,
+}
+
+impl CaptureWriter {
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            writer: StdMutex::new(BufWriter::new(file)),
+        })
+    }
+
+    pub fn record(&self, message: &Message) {
+        let message = match CapturedMessage::from_message(message) {
+            Some(message) => message,
+            None => return,
+        };
+        let frame = CapturedFrame {
+            timestamp: unix_millis(SystemTime::now()),
+            message,
+        };
+
+        let mut writer = self.writer.lock().unwrap();
+        let result = serde_json::to_writer(&mut *writer, &frame)
+            .map_err(std::io::Error::from)
+            .and_then(|_| writer.write_all(b"\n"))
+            .and_then(|_| writer.flush());
+        if let Err(e) = result {
+            error!("Failed to write captured frame: {:?}", e);
+        }
+    }
+}
+
+pub fn read_capture(path: &Path) -> std::io::Result<Vec<CapturedFrame>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut frames = vec![];
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        frames.push(serde_json::from_str(&line)?);
+    }
+    Ok(frames)
+}
@@ -0,0 +1,149 @@
+use std::env;
+use std::env::VarError;
+use std::fmt::Debug;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use tokio::time::Duration;
+use tokio_tungstenite::tungstenite::http::{HeaderValue, Uri};
+
+#[derive(Debug, Clone)]
+pub struct LobbyCacheConfig {
+    pub url: Uri,
+    // Served while the primary url is down
+    pub fallback_url: Option<Uri>,
+    pub origin: HeaderValue,
+    pub user_agent: String,
+    // Reconnect delays start at reconnect_min_delay and double after every consecutive failure
+    pub reconnect_min_delay: Duration,
+    pub reconnect_max_delay: Duration,
+    // Fraction by which each reconnect delay is randomly shortened
+    pub reconnect_jitter: f64,
+    // Consecutive failures after which upstream is reported as down
+    pub circuit_breaker_threshold: u32,
+    // Reconnect if no message was received for this long
+    pub idle_timeout: Duration,
+    // Reconnect for a fresh full snapshot after this long, 0 disables periodic resyncs
+    pub resync_interval: Duration,
+    // Append every received frame to this JSONL file
+    pub capture_path: Option<PathBuf>,
+    // Write unparseable frames to this directory, keeping the latest dead_letter_max_files
+    pub dead_letter_path: Option<PathBuf>,
+    pub dead_letter_max_files: usize,
+    // Feed frames from a capture file instead of connecting to the websocket
+    pub replay_path: Option<PathBuf>,
+    // Playback speed multiplier for replays, 0 replays without any delay
+    pub replay_speed: f64,
+    // Cached lobbies are considered stale when upstream hasn't updated them for this long
+    pub stale_after: Duration,
+    // Periodically persist the cached lobbies here and restore them on startup
+    pub snapshot_path: Option<PathBuf>,
+    pub snapshot_interval: Duration,
+    // Snapshots older than this are ignored on startup
+    pub snapshot_max_age: Duration,
+    // SQLite file recording lobby creations, joins, leaves and deletions
+    pub history_path: Option<PathBuf>,
+}
+
+impl Default for LobbyCacheConfig {
+    fn default() -> Self {
+        Self {
+            url: Uri::from_static("wss://aoe2lobby.com/ws/lobby/"),
+            fallback_url: None,
+            origin: HeaderValue::from_static("https://aoe2lobby.com"),
+            user_agent: "Lobby-is-up-bot;Repo:https://github.com/L1ghtman2k/lobby-is-up"
+                .to_string(),
+            reconnect_min_delay: Duration::from_secs(5),
+            reconnect_max_delay: Duration::from_secs(5 * 60),
+            reconnect_jitter: 0.3,
+            circuit_breaker_threshold: 5,
+            idle_timeout: Duration::from_secs(20),
+            resync_interval: Duration::from_secs(30 * 60),
+            capture_path: None,
+            dead_letter_path: None,
+            dead_letter_max_files: 100,
+            replay_path: None,
+            replay_speed: 1.0,
+            stale_after: Duration::from_secs(60),
+            snapshot_path: None,
+            snapshot_interval: Duration::from_secs(60),
+            snapshot_max_age: Duration::from_secs(10 * 60),
+            history_path: None,
+        }
+    }
+}
+
+impl LobbyCacheConfig {
+    // Every setting falls back to its default when the environment variable is not set
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            url: env_or("AOE2LOBBY_URL", default.url),
+            fallback_url: env_opt("AOE2LOBBY_FALLBACK_URL"),
+            origin: env_or("AOE2LOBBY_ORIGIN", default.origin),
+            user_agent: env_or("AOE2LOBBY_USER_AGENT", default.user_agent),
+            reconnect_min_delay: env_secs_or(
+                "AOE2LOBBY_RECONNECT_MIN_SECS",
+                default.reconnect_min_delay,
+            ),
+            reconnect_max_delay: env_secs_or(
+                "AOE2LOBBY_RECONNECT_MAX_SECS",
+                default.reconnect_max_delay,
+            ),
+            reconnect_jitter: env_or("AOE2LOBBY_RECONNECT_JITTER", default.reconnect_jitter),
+            circuit_breaker_threshold: env_or(
+                "AOE2LOBBY_CIRCUIT_BREAKER_FAILURES",
+                default.circuit_breaker_threshold,
+            ),
+            idle_timeout: env_secs_or("AOE2LOBBY_IDLE_TIMEOUT_SECS", default.idle_timeout),
+            resync_interval: env_secs_or("AOE2LOBBY_RESYNC_INTERVAL_SECS", default.resync_interval),
+            capture_path: env_opt("AOE2LOBBY_CAPTURE_FILE"),
+            dead_letter_path: env_opt("AOE2LOBBY_DEAD_LETTER_DIR"),
+            dead_letter_max_files: env_or(
+                "AOE2LOBBY_DEAD_LETTER_MAX_FILES",
+                default.dead_letter_max_files,
+            ),
+            replay_path: env_opt("AOE2LOBBY_REPLAY_FILE"),
+            replay_speed: env_or("AOE2LOBBY_REPLAY_SPEED", default.replay_speed),
+            stale_after: env_secs_or("AOE2LOBBY_STALE_AFTER_SECS", default.stale_after),
+            snapshot_path: env_opt("AOE2LOBBY_SNAPSHOT_FILE"),
+            snapshot_interval: env_secs_or(
+                "AOE2LOBBY_SNAPSHOT_INTERVAL_SECS",
+                default.snapshot_interval,
+            ),
+            snapshot_max_age: env_secs_or(
+                "AOE2LOBBY_SNAPSHOT_MAX_AGE_SECS",
+                default.snapshot_max_age,
+            ),
+            history_path: env_opt("AOE2LOBBY_HISTORY_FILE"),
+        }
+    }
+}
+
+fn env_opt<T>(name: &str) -> Option<T>
+where
+    T: FromStr,
+    T::Err: Debug,
+{
+    match env::var(name) {
+        Ok(value) => Some(
+            value
+                .parse()
+                .unwrap_or_else(|e| panic!("{} has an invalid value: {:?}", name, e)),
+        ),
+        Err(VarError::NotPresent) => None,
+        Err(VarError::NotUnicode(_)) => panic!("{} must be valid unicode", name),
+    }
+}
+
+fn env_or<T>(name: &str, default: T) -> T
+where
+    T: FromStr,
+    T::Err: Debug,
+{
+    env_opt(name).unwrap_or(default)
+}
+
+fn env_secs_or(name: &str, default: Duration) -> Duration {
+    Duration::from_secs(env_or(name, default.as_secs()))
+}
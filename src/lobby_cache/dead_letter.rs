@@ -0,0 +1,157 @@
+use crate::lobby_cache::model::{
+    UpstreamFrame, WebsocketMessageReceiveAllCurrentLobbies, WebsocketMessageReceiveFollowUp,
+};
+use crate::lobby_cache::util::unix_millis;
+use crate::metrics;
+use serde::de::DeserializeOwned;
+use serde_derive::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex as StdMutex;
+use std::time::SystemTime;
+use tracing::{error, warn};
+use uuid::Uuid;
+
+// One unparseable upstream frame, stored as a json file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeadLetter {
+    // Milliseconds since the unix epoch at which the frame was received
+    pub timestamp: u64,
+    pub source: String,
+    pub message: String,
+    pub errors: Vec<FrameError>,
+}
+
+// Where deserializing a message as one of the known frames failed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FrameError {
+    pub frame: String,
+    pub path: String,
+    pub error: String,
+}
+
+// Keeps the latest unparseable frames in a directory, removing the oldest ones past max_files
+pub struct DeadLetterWriter {
+    dir: PathBuf,
+    max_files: usize,
+    // Serializes rotations between sources sharing the directory
+    lock: StdMutex<()>,
+}
+
+impl DeadLetterWriter {
+    pub fn open(dir: &Path, max_files: usize) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            max_files,
+            lock: StdMutex::new(()),
+        })
+    }
+
+    pub fn record(&self, source: &str, message: &str) {
+        let dead_letter = DeadLetter {
+            timestamp: unix_millis(SystemTime::now()),
+            source: source.to_string(),
+            message: message.to_string(),
+            errors: frame_errors(message.as_bytes()),
+        };
+        // Zero padded timestamps keep file names sorted by age
+        let path = self.dir.join(format!(
+            "{:013}-{}.json",
+            dead_letter.timestamp,
+            Uuid::new_v4()
+        ));
+
+        let _guard = self.lock.lock().unwrap();
+        let result = serde_json::to_vec_pretty(&dead_letter)
+            .map_err(io::Error::from)
+            .and_then(|data| fs::write(&path, data));
+        match result {
+            Ok(()) => {
+                metrics::DEAD_LETTERS.inc();
+                warn!("Wrote unparseable frame to {}", path.display());
+            }
+            Err(e) => error!("Failed to write dead letter {}: {:?}", path.display(), e),
+        }
+        if let Err(e) = self.rotate() {
+            error!("Failed to rotate dead letters: {:?}", e);
+        }
+    }
+
+    fn rotate(&self) -> io::Result<()> {
+        let files = list_dead_letters(&self.dir)?;
+        if files.len() > self.max_files {
+            for path in &files[..files.len() - self.max_files] {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// The dead letter files of a directory, oldest first
+pub fn list_dead_letters(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if matches!(path.extension(), Some(extension) if extension == "json") {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+pub fn read_dead_letter(path: &Path) -> io::Result<DeadLetter> {
+    Ok(serde_json::from_slice(&fs::read(path)?)?)
+}
+
+// Deserializes the data as every known frame, keeping the path at which each attempt failed
+pub fn frame_errors(data: &[u8]) -> Vec<FrameError> {
+    let mut errors = vec![];
+    if let Err(e) = serde_json::from_slice::<serde_json::Value>(data) {
+        errors.push(FrameError {
+            frame: "json".to_string(),
+            path: ".".to_string(),
+            error: e.to_string(),
+        });
+        return errors;
+    }
+    errors.extend(frame_error::<WebsocketMessageReceiveAllCurrentLobbies>(
+        "allcurrentlobbies",
+        data,
+    ));
+    errors.extend(frame_error::<WebsocketMessageReceiveFollowUp>(
+        "followup", data,
+    ));
+    errors
+}
+
+fn frame_error<T: DeserializeOwned>(frame: &str, data: &[u8]) -> Option<FrameError> {
+    let deserializer = &mut serde_json::Deserializer::from_slice(data);
+    serde_path_to_error::deserialize::<_, T>(deserializer)
+        .err()
+        .map(|e| FrameError {
+            frame: frame.to_string(),
+            path: e.path().to_string(),
+            error: e.into_inner().to_string(),
+        })
+}
+
+// Parses a dead letter again with the current model, returning which frame it now parses as.
+// Lobbies that still don't parse fail it, even when the rest of the frame does
+pub fn reparse(dead_letter: &DeadLetter) -> serde_json::Result<&'static str> {
+    let parsed = UpstreamFrame::parse(dead_letter.message.as_bytes())?;
+    if !parsed.dropped_lobbies.is_empty() {
+        return Err(serde::de::Error::custom(format!(
+            "lobbies {} don't parse",
+            parsed.dropped_lobbies.join(", ")
+        )));
+    }
+    match parsed.frame {
+        UpstreamFrame::AllCurrentLobbies(_) => Ok("allcurrentlobbies"),
+        UpstreamFrame::FollowUp(_) => Ok("followup"),
+        UpstreamFrame::Unknown(_) => Ok("unknown"),
+    }
+}
@@ -12,19 +12,27 @@ pub enum LobbyCacheError {
     Parsing(#[from] MessageParsingError),
 }
 
+#[derive(Debug, Error)]
+pub enum HistoryError {
+    #[error("sqlite: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+
+    #[error("history task: {0}")]
+    Task(#[from] tokio::task::JoinError),
+}
+
 #[derive(Debug, Error)]
 pub struct MessageParsingError {
     pub message: String,
-    pub error_parsing_all_messages: serde_json::Error,
-    pub error_parsing_followup_message: serde_json::Error,
+    pub error: serde_json::Error,
 }
 
 impl Display for MessageParsingError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            r#"Failed to parse incoming message. error_parsing_all_messages: "{}", error_parsing_followup_message: "{}""#,
-            self.error_parsing_all_messages, self.error_parsing_followup_message
+            r#"Failed to parse incoming message. error: "{}", message: "{}""#,
+            self.error, self.message
         )
     }
 }
@@ -0,0 +1,162 @@
+use crate::lobby_cache::model::Lobby;
+use std::fmt;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LobbyEvent {
+    // Shared with the store, so events don't copy lobbies
+    Created(Arc<Lobby>),
+    Updated {
+        previous: Arc<Lobby>,
+        current: Arc<Lobby>,
+    },
+    Deleted {
+        lobby: Arc<Lobby>,
+        reason: DeletionReason,
+    },
+    // The cache was cleared and repopulated from a full snapshot, subscribers should re-read
+    Reset,
+}
+
+// Why a lobby most likely disappeared, guessed from its last known state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeletionReason {
+    // The lobby was full, the game probably started
+    Launched,
+    // Every player or the host left
+    Abandoned,
+    // Closed while players were still waiting for others
+    Closed,
+}
+
+impl DeletionReason {
+    pub fn classify(lobby: &Lobby) -> Self {
+        let players: Vec<_> = lobby
+            .slot
+            .values()
+            .filter(|slot| slot.name.is_some() && slot.spectator != Some(true))
+            .collect();
+        if !players.is_empty() && lobby.slotstaken >= lobby.slotstotal {
+            return DeletionReason::Launched;
+        }
+        // Prefer the profile id, host names aren't unique
+        let host_present = match (lobby.hostprofileid, &lobby.host) {
+            (Some(host_id), _) => players.iter().any(|slot| slot.profileid == Some(host_id)),
+            (None, Some(host)) => players.iter().any(|slot| slot.name.as_ref() == Some(host)),
+            (None, None) => true,
+        };
+        if players.is_empty() || !host_present {
+            DeletionReason::Abandoned
+        } else {
+            DeletionReason::Closed
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeletionReason::Launched => "launched",
+            DeletionReason::Abandoned => "abandoned",
+            DeletionReason::Closed => "closed",
+        }
+    }
+
+    pub fn parse(reason: &str) -> Option<Self> {
+        match reason {
+            "launched" => Some(DeletionReason::Launched),
+            "abandoned" => Some(DeletionReason::Abandoned),
+            "closed" => Some(DeletionReason::Closed),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for DeletionReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lobby_cache::model::Slot;
+
+    fn player(name: &str, profileid: i64) -> Slot {
+        Slot {
+            name: Some(name.to_string()),
+            profileid: Some(profileid),
+            ..Slot::default()
+        }
+    }
+
+    fn lobby(slots: Vec<Slot>, slotstotal: i64) -> Lobby {
+        Lobby {
+            slotstaken: slots.len() as i64,
+            slotstotal,
+            slot: slots
+                .into_iter()
+                .enumerate()
+                .map(|(index, slot)| ((index + 1).to_string(), slot))
+                .collect(),
+            host: Some("Alice".to_string()),
+            hostprofileid: Some(1),
+            ..Lobby::default()
+        }
+    }
+
+    #[test]
+    fn full_lobbies_launched() {
+        let lobby = lobby(vec![player("Alice", 1), player("Bob", 2)], 2);
+        assert_eq!(DeletionReason::classify(&lobby), DeletionReason::Launched);
+    }
+
+    #[test]
+    fn lobbies_without_players_or_host_were_abandoned() {
+        assert_eq!(
+            DeletionReason::classify(&lobby(vec![], 2)),
+            DeletionReason::Abandoned
+        );
+        let without_host = lobby(vec![player("Bob", 2)], 4);
+        assert_eq!(
+            DeletionReason::classify(&without_host),
+            DeletionReason::Abandoned
+        );
+        // Another player named like the host isn't the host
+        let impostor = lobby(vec![player("Alice", 3)], 4);
+        assert_eq!(
+            DeletionReason::classify(&impostor),
+            DeletionReason::Abandoned
+        );
+    }
+
+    #[test]
+    fn lobbies_closed_by_a_waiting_host_were_closed() {
+        let mut waiting = lobby(vec![player("Alice", 1)], 4);
+        assert_eq!(DeletionReason::classify(&waiting), DeletionReason::Closed);
+        // Without a profile id the host is matched by name
+        waiting.hostprofileid = None;
+        assert_eq!(DeletionReason::classify(&waiting), DeletionReason::Closed);
+    }
+
+    #[test]
+    fn spectators_dont_count_as_players() {
+        let spectator = Slot {
+            spectator: Some(true),
+            ..player("Alice", 1)
+        };
+        let lobby = lobby(vec![spectator], 1);
+        assert_eq!(DeletionReason::classify(&lobby), DeletionReason::Abandoned);
+    }
+
+    #[test]
+    fn reasons_round_trip_through_their_names() {
+        for reason in [
+            DeletionReason::Launched,
+            DeletionReason::Abandoned,
+            DeletionReason::Closed,
+        ] {
+            assert_eq!(DeletionReason::parse(reason.as_str()), Some(reason));
+        }
+        assert_eq!(DeletionReason::parse("exploded"), None);
+    }
+}
@@ -0,0 +1,322 @@
+use crate::lobby_cache::error::HistoryError;
+use crate::lobby_cache::event::DeletionReason;
+use crate::lobby_cache::model::Lobby;
+use crate::lobby_cache::util::unix_millis;
+use rusqlite::{params, Connection};
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryKind {
+    Created,
+    Joined,
+    Left,
+    DescriptionChanged,
+    Deleted,
+}
+
+impl HistoryKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            HistoryKind::Created => "created",
+            HistoryKind::Joined => "joined",
+            HistoryKind::Left => "left",
+            HistoryKind::DescriptionChanged => "description_changed",
+            HistoryKind::Deleted => "deleted",
+        }
+    }
+
+    fn parse(kind: &str) -> Option<Self> {
+        match kind {
+            "created" => Some(HistoryKind::Created),
+            "joined" => Some(HistoryKind::Joined),
+            "left" => Some(HistoryKind::Left),
+            "description_changed" => Some(HistoryKind::DescriptionChanged),
+            "deleted" => Some(HistoryKind::Deleted),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryEntry {
+    pub lobby_id: i64,
+    pub timestamp: SystemTime,
+    pub kind: HistoryKind,
+    // Set for joins and leaves
+    pub player: Option<String>,
+    // The description for creations and description changes, the deletion reason for deletions
+    pub detail: Option<String>,
+}
+
+// Append only SQLite log of what happened to every lobby
+pub struct HistoryStore {
+    connection: Arc<StdMutex<Connection>>,
+}
+
+impl HistoryStore {
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS lobby_history (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                lobby_id INTEGER NOT NULL,
+                timestamp_ms INTEGER NOT NULL,
+                kind TEXT NOT NULL,
+                player TEXT,
+                detail TEXT
+            );
+            CREATE INDEX IF NOT EXISTS lobby_history_lobby_id ON lobby_history (lobby_id, id);",
+        )?;
+        Ok(Self {
+            connection: Arc::new(StdMutex::new(connection)),
+        })
+    }
+
+    pub async fn record(&self, entries: Vec<HistoryEntry>) {
+        if entries.is_empty() {
+            return;
+        }
+        let connection = self.connection.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            let mut connection = connection.lock().unwrap();
+            let transaction = connection.transaction()?;
+            for entry in entries {
+                transaction.execute(
+                    "INSERT INTO lobby_history (lobby_id, timestamp_ms, kind, player, detail)
+                     VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![
+                        entry.lobby_id,
+                        unix_millis(entry.timestamp) as i64,
+                        entry.kind.as_str(),
+                        entry.player,
+                        entry.detail
+                    ],
+                )?;
+            }
+            transaction.commit()
+        })
+        .await;
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => error!("Failed to record lobby history: {:?}", e),
+            Err(e) => error!("Lobby history task failed: {:?}", e),
+        }
+    }
+
+    // Returns the latest entries of a lobby, oldest first
+    pub async fn timeline(
+        &self,
+        lobby_id: i64,
+        limit: usize,
+    ) -> Result<Vec<HistoryEntry>, HistoryError> {
+        let connection = self.connection.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            let connection = connection.lock().unwrap();
+            let mut statement = connection.prepare(
+                "SELECT lobby_id, timestamp_ms, kind, player, detail FROM lobby_history
+                 WHERE lobby_id = ?1 ORDER BY id DESC LIMIT ?2",
+            )?;
+            let rows = statement.query_map(params![lobby_id, limit as i64], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, Option<String>>(3)?,
+                    row.get::<_, Option<String>>(4)?,
+                ))
+            })?;
+
+            let mut entries = vec![];
+            for row in rows {
+                let (lobby_id, timestamp_ms, kind, player, detail) = row?;
+                if let Some(kind) = HistoryKind::parse(&kind) {
+                    entries.push(HistoryEntry {
+                        lobby_id,
+                        timestamp: UNIX_EPOCH + Duration::from_millis(timestamp_ms as u64),
+                        kind,
+                        player,
+                        detail,
+                    });
+                }
+            }
+            entries.reverse();
+            Ok::<_, rusqlite::Error>(entries)
+        })
+        .await;
+        Ok(result??)
+    }
+}
+
+// Derives the history entries between two consecutive states of a lobby, None meaning the lobby
+// doesn't exist
+pub fn diff(
+    previous: Option<&Lobby>,
+    current: Option<&Lobby>,
+    timestamp: SystemTime,
+) -> Vec<HistoryEntry> {
+    let lobby_id = match current.or(previous) {
+        Some(lobby) => lobby.lobbyid,
+        None => return vec![],
+    };
+    let entry = |kind, player, detail| HistoryEntry {
+        lobby_id,
+        timestamp,
+        kind,
+        player,
+        detail,
+    };
+
+    let mut entries = vec![];
+    match (previous, current) {
+        (None, Some(current)) => {
+            entries.push(entry(
+                HistoryKind::Created,
+                None,
+                Some(current.description.clone()),
+            ));
+        }
+        (Some(previous), Some(current)) if previous.description != current.description => {
+            entries.push(entry(
+                HistoryKind::DescriptionChanged,
+                None,
+                Some(current.description.clone()),
+            ));
+        }
+        _ => {}
+    }
+
+    // Players of a deleted lobby usually started the game, so they aren't recorded as leaving
+    let current = match current {
+        Some(current) => current,
+        None => {
+            let reason = previous.map(|previous| DeletionReason::classify(previous).to_string());
+            entries.push(entry(HistoryKind::Deleted, None, reason));
+            return entries;
+        }
+    };
+    let previous_players = previous.map(player_names).unwrap_or_default();
+    let current_players = player_names(current);
+    for player in previous_players.difference(&current_players) {
+        entries.push(entry(HistoryKind::Left, Some(player.clone()), None));
+    }
+    for player in current_players.difference(&previous_players) {
+        entries.push(entry(HistoryKind::Joined, Some(player.clone()), None));
+    }
+    entries
+}
+
+fn player_names(lobby: &Lobby) -> BTreeSet<String> {
+    lobby
+        .slot
+        .values()
+        .filter_map(|slot| slot.name.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lobby_cache::model::Slot;
+
+    fn lobby(description: &str, players: &[&str]) -> Lobby {
+        Lobby {
+            lobbyid: 7,
+            description: description.to_string(),
+            slotstaken: players.len() as i64,
+            slotstotal: 4,
+            slot: players
+                .iter()
+                .enumerate()
+                .map(|(index, name)| {
+                    let slot = Slot {
+                        name: Some(name.to_string()),
+                        ..Slot::default()
+                    };
+                    ((index + 1).to_string(), slot)
+                })
+                .collect(),
+            ..Lobby::default()
+        }
+    }
+
+    fn kinds(entries: &[HistoryEntry]) -> Vec<(HistoryKind, Option<&str>)> {
+        entries
+            .iter()
+            .map(|entry| (entry.kind, entry.player.as_deref()))
+            .collect()
+    }
+
+    #[test]
+    fn records_creation_and_initial_players() {
+        let current = lobby("2v2 arabia", &["Alice"]);
+        let entries = diff(None, Some(&current), SystemTime::now());
+        assert_eq!(
+            kinds(&entries),
+            vec![
+                (HistoryKind::Created, None),
+                (HistoryKind::Joined, Some("Alice"))
+            ]
+        );
+        assert_eq!(entries[0].detail.as_deref(), Some("2v2 arabia"));
+        assert!(entries.iter().all(|entry| entry.lobby_id == 7));
+    }
+
+    #[test]
+    fn records_joins_leaves_and_description_changes() {
+        let previous = lobby("2v2 arabia", &["Alice", "Bob"]);
+        let current = lobby("2v2 arabia, no rush", &["Alice", "Carol"]);
+        let entries = diff(Some(&previous), Some(&current), SystemTime::now());
+        assert_eq!(
+            kinds(&entries),
+            vec![
+                (HistoryKind::DescriptionChanged, None),
+                (HistoryKind::Left, Some("Bob")),
+                (HistoryKind::Joined, Some("Carol")),
+            ]
+        );
+    }
+
+    #[test]
+    fn records_nothing_for_unchanged_lobbies() {
+        let lobby = lobby("2v2 arabia", &["Alice"]);
+        assert!(diff(Some(&lobby), Some(&lobby), SystemTime::now()).is_empty());
+        assert!(diff(None, None, SystemTime::now()).is_empty());
+    }
+
+    #[test]
+    fn records_deletions_without_players_leaving() {
+        let previous = lobby("2v2 arabia", &["Alice", "Bob"]);
+        let entries = diff(Some(&previous), None, SystemTime::now());
+        assert_eq!(kinds(&entries), vec![(HistoryKind::Deleted, None)]);
+        // Players were still waiting for others
+        assert_eq!(entries[0].detail.as_deref(), Some("closed"));
+    }
+
+    #[tokio::test]
+    async fn returns_the_latest_entries_oldest_first() {
+        let history = HistoryStore::open(Path::new(":memory:")).unwrap();
+        let started = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let current = lobby("2v2 arabia", &["Alice"]);
+        history.record(diff(None, Some(&current), started)).await;
+        let next = lobby("2v2 arabia", &["Alice", "Bob"]);
+        let joined_at = started + Duration::from_secs(5);
+        history
+            .record(diff(Some(&current), Some(&next), joined_at))
+            .await;
+
+        let entries = history.timeline(7, 2).await.unwrap();
+        assert_eq!(
+            kinds(&entries),
+            vec![
+                (HistoryKind::Joined, Some("Alice")),
+                (HistoryKind::Joined, Some("Bob"))
+            ]
+        );
+        assert_eq!(entries[1].timestamp, joined_at);
+        assert!(history.timeline(8, 10).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,264 @@
+use crate::lobby_cache::model::Lobby;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+// Secondary indexes over the cached lobbies, every key maps to the ids of the matching lobbies
+#[derive(Debug, Default)]
+pub struct LobbyIndex {
+    players: HashMap<String, HashSet<String>>,
+    regions: HashMap<String, HashSet<String>>,
+    open_slots: BTreeMap<i64, HashSet<String>>,
+    words: HashMap<String, HashSet<String>>,
+}
+
+// Every set field must match, an empty query matches every indexed lobby
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LobbyQuery {
+    pub player: Option<String>,
+    pub region: Option<String>,
+    pub min_open_slots: Option<i64>,
+    pub words: Vec<String>,
+}
+
+impl LobbyIndex {
+    pub fn insert(&mut self, lobby_id: &str, lobby: &Lobby) {
+        for name in player_names(lobby) {
+            add(&mut self.players, name, lobby_id);
+        }
+        add(
+            &mut self.regions,
+            lobby.relayserver_region.to_lowercase(),
+            lobby_id,
+        );
+        self.open_slots
+            .entry(open_slots(lobby))
+            .or_default()
+            .insert(lobby_id.to_string());
+        for word in tokenize(&lobby.description) {
+            add(&mut self.words, word, lobby_id);
+        }
+    }
+
+    // Must be given the lobby as it was inserted
+    pub fn remove(&mut self, lobby_id: &str, lobby: &Lobby) {
+        for name in player_names(lobby) {
+            remove(&mut self.players, &name, lobby_id);
+        }
+        remove(
+            &mut self.regions,
+            &lobby.relayserver_region.to_lowercase(),
+            lobby_id,
+        );
+        let slots = open_slots(lobby);
+        if let Some(ids) = self.open_slots.get_mut(&slots) {
+            ids.remove(lobby_id);
+            if ids.is_empty() {
+                self.open_slots.remove(&slots);
+            }
+        }
+        for word in tokenize(&lobby.description) {
+            remove(&mut self.words, &word, lobby_id);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    // Returns the ids of the lobbies matching the query
+    pub fn search(&self, query: &LobbyQuery) -> HashSet<String> {
+        let mut candidates: Vec<HashSet<String>> = vec![];
+        if let Some(player) = &query.player {
+            candidates.push(lookup(&self.players, &player.to_lowercase()));
+        }
+        if let Some(region) = &query.region {
+            candidates.push(lookup(&self.regions, &region.to_lowercase()));
+        }
+        if let Some(min_open_slots) = query.min_open_slots {
+            candidates.push(
+                self.open_slots
+                    .range(min_open_slots..)
+                    .flat_map(|(_, ids)| ids.iter().cloned())
+                    .collect(),
+            );
+        }
+        for word in query.words.iter().flat_map(|words| tokenize(words)) {
+            candidates.push(lookup(&self.words, &word));
+        }
+
+        // Intersect starting from the smallest set
+        candidates.sort_by_key(HashSet::len);
+        let mut candidates = candidates.into_iter();
+        let mut matches = match candidates.next() {
+            Some(first) => first,
+            None => return self.open_slots.values().flatten().cloned().collect(),
+        };
+        for ids in candidates {
+            matches.retain(|lobby_id| ids.contains(lobby_id));
+        }
+        matches
+    }
+}
+
+fn add(index: &mut HashMap<String, HashSet<String>>, key: String, lobby_id: &str) {
+    index.entry(key).or_default().insert(lobby_id.to_string());
+}
+
+fn remove(index: &mut HashMap<String, HashSet<String>>, key: &str, lobby_id: &str) {
+    if let Some(ids) = index.get_mut(key) {
+        ids.remove(lobby_id);
+        if ids.is_empty() {
+            index.remove(key);
+        }
+    }
+}
+
+fn lookup(index: &HashMap<String, HashSet<String>>, key: &str) -> HashSet<String> {
+    index.get(key).cloned().unwrap_or_default()
+}
+
+fn player_names(lobby: &Lobby) -> HashSet<String> {
+    lobby
+        .slot
+        .values()
+        .filter_map(|slot| slot.name.as_ref())
+        .map(|name| name.to_lowercase())
+        .collect()
+}
+
+fn open_slots(lobby: &Lobby) -> i64 {
+    (lobby.slotstotal - lobby.slotstaken).max(0)
+}
+
+// Lowercased words of the text, punctuation excluded
+pub fn tokenize(text: &str) -> HashSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lobby_cache::model::Slot;
+
+    fn lobby(region: &str, description: &str, players: &[&str], slotstotal: i64) -> Lobby {
+        Lobby {
+            relayserver_region: region.into(),
+            description: description.to_string(),
+            slotstaken: players.len() as i64,
+            slotstotal,
+            slot: players
+                .iter()
+                .enumerate()
+                .map(|(index, name)| {
+                    let slot = Slot {
+                        name: Some(name.to_string()),
+                        ..Slot::default()
+                    };
+                    ((index + 1).to_string(), slot)
+                })
+                .collect(),
+            ..Lobby::default()
+        }
+    }
+
+    fn index() -> LobbyIndex {
+        let mut index = LobbyIndex::default();
+        index.insert(
+            "1",
+            &lobby("westeurope", "2v2 Arabia!", &["Alice", "Bob"], 4),
+        );
+        index.insert("2", &lobby("eastus", "1v1 arabia", &["Carol"], 2));
+        index.insert("3", &lobby("westeurope", "FFA nomad", &["alice"], 8));
+        index
+    }
+
+    fn ids(ids: &[&str]) -> HashSet<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn empty_query_matches_every_lobby() {
+        assert_eq!(
+            index().search(&LobbyQuery::default()),
+            ids(&["1", "2", "3"])
+        );
+    }
+
+    #[test]
+    fn matches_players_regions_and_words_case_insensitively() {
+        let index = index();
+        let query = |query: LobbyQuery| index.search(&query);
+        assert_eq!(
+            query(LobbyQuery {
+                player: Some("ALICE".to_string()),
+                ..LobbyQuery::default()
+            }),
+            ids(&["1", "3"])
+        );
+        assert_eq!(
+            query(LobbyQuery {
+                region: Some("WestEurope".to_string()),
+                ..LobbyQuery::default()
+            }),
+            ids(&["1", "3"])
+        );
+        assert_eq!(
+            query(LobbyQuery {
+                words: vec!["arabia".to_string()],
+                ..LobbyQuery::default()
+            }),
+            ids(&["1", "2"])
+        );
+    }
+
+    #[test]
+    fn intersects_every_set_field() {
+        let index = index();
+        let query = LobbyQuery {
+            region: Some("westeurope".to_string()),
+            min_open_slots: Some(2),
+            words: vec!["arabia, 2v2".to_string()],
+            ..LobbyQuery::default()
+        };
+        assert_eq!(index.search(&query), ids(&["1"]));
+
+        let query = LobbyQuery {
+            min_open_slots: Some(3),
+            ..LobbyQuery::default()
+        };
+        assert_eq!(index.search(&query), ids(&["3"]));
+
+        let query = LobbyQuery {
+            player: Some("carol".to_string()),
+            region: Some("westeurope".to_string()),
+            ..LobbyQuery::default()
+        };
+        assert!(index.search(&query).is_empty());
+    }
+
+    #[test]
+    fn removed_lobbies_no_longer_match() {
+        let mut index = index();
+        index.remove(
+            "1",
+            &lobby("westeurope", "2v2 Arabia!", &["Alice", "Bob"], 4),
+        );
+        let query = LobbyQuery {
+            player: Some("bob".to_string()),
+            ..LobbyQuery::default()
+        };
+        assert!(index.search(&query).is_empty());
+        assert!(!index.players.contains_key("bob"));
+        assert_eq!(index.search(&LobbyQuery::default()), ids(&["2", "3"]));
+    }
+
+    #[test]
+    fn tokenizes_without_punctuation() {
+        assert_eq!(
+            tokenize("2v2 -- Arabia, no_rush!"),
+            ids(&["2v2", "arabia", "no", "rush"])
+        );
+    }
+}
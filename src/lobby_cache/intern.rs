@@ -0,0 +1,92 @@
+use once_cell::sync::Lazy;
+use serde::de::{self, Deserialize, Deserializer, Visitor};
+use serde::ser::{Serialize, Serializer};
+use std::collections::HashSet;
+use std::fmt;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex as StdMutex};
+
+// Only relay regions, civs, colors and teams are interned. They come from the game's small fixed
+// vocabularies, so the table stays bounded without ever evicting. Text players choose, like
+// custom map names, must stay owned strings
+static INTERNER: Lazy<StdMutex<HashSet<Arc<str>>>> = Lazy::new(|| StdMutex::new(HashSet::new()));
+
+// An immutable string shared by every lobby holding the same value
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Symbol(Arc<str>);
+
+impl Symbol {
+    pub fn new(value: &str) -> Self {
+        let mut interner = INTERNER.lock().unwrap();
+        if let Some(interned) = interner.get(value) {
+            return Symbol(interned.clone());
+        }
+        let interned: Arc<str> = Arc::from(value);
+        interner.insert(interned.clone());
+        Symbol(interned)
+    }
+}
+
+// Number of distinct interned strings
+pub fn interned_count() -> usize {
+    INTERNER.lock().unwrap().len()
+}
+
+impl Default for Symbol {
+    fn default() -> Self {
+        Symbol::new("")
+    }
+}
+
+impl Deref for Symbol {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<&str> for Symbol {
+    fn from(value: &str) -> Self {
+        Symbol::new(value)
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&*self.0, f)
+    }
+}
+
+impl Serialize for Symbol {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Symbol {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_str(SymbolVisitor)
+    }
+}
+
+struct SymbolVisitor;
+
+impl<'de> Visitor<'de> for SymbolVisitor {
+    type Value = Symbol;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a string")
+    }
+
+    // Borrowed and transient strings are looked up without allocating a copy first
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Symbol, E> {
+        Ok(Symbol::new(value))
+    }
+}
@@ -1,291 +1,839 @@
+pub mod capture;
+pub mod config;
+pub mod dead_letter;
 pub mod error;
+pub mod event;
+pub mod history;
+pub mod index;
+pub mod intern;
 pub mod model;
-
-use crate::lobby_cache::model::{
-    Lobby, WebsocketMessageReceiveAllCurrentLobbies, WebsocketMessageReceiveFollowUp,
-};
-use dashmap::DashMap;
-use once_cell::sync::Lazy;
+pub mod reconnect;
+pub mod snapshot;
+pub mod source;
+pub mod state;
+pub mod store;
+pub mod util;
+
+use crate::lobby_cache::config::LobbyCacheConfig;
+use crate::lobby_cache::event::LobbyEvent;
+use crate::lobby_cache::history::HistoryStore;
+use crate::lobby_cache::index::LobbyQuery;
+use crate::lobby_cache::model::Lobby;
+use crate::lobby_cache::reconnect::ReconnectStatus;
+use crate::lobby_cache::snapshot::Snapshot;
+use crate::lobby_cache::source::{LobbySource, SourceUpdate};
+use crate::lobby_cache::state::ConnectionState;
+use crate::lobby_cache::store::{LobbyStore, MemoryLobbyStore, WatchedLobby};
+use crate::metrics;
+use scopeguard::defer;
+use serenity::async_trait;
 use std::collections::HashMap;
-
-use std::str::FromStr;
-
-use serenity::futures::StreamExt;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex as StdMutex, Once};
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
 use std::time;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
 
-use tokio::sync::broadcast;
+pub struct LobbyCache {
+    config: LobbyCacheConfig,
+    store: MemoryLobbyStore,
+    history: Option<Arc<HistoryStore>>,
+    connection_state: watch::Sender<ConnectionState>,
+}
 
-use tokio::sync::{mpsc, Mutex as TokioMutex};
-use tokio::time::{sleep, Duration};
+impl LobbyCache {
+    pub fn new(config: LobbyCacheConfig) -> Self {
+        let history = config.history_path.as_ref().map(|path| {
+            let store = HistoryStore::open(path).unwrap_or_else(|e| {
+                panic!("Failed to open history store {}: {:?}", path.display(), e)
+            });
+            Arc::new(store)
+        });
 
-use tokio_tungstenite::connect_async;
+        LobbyCache {
+            store: MemoryLobbyStore::new(config.stale_after, config.snapshot_max_age),
+            config,
+            history,
+            connection_state: watch::channel(ConnectionState::Connecting).0,
+        }
+    }
 
-use tokio_tungstenite::tungstenite::error::UrlError;
-use tokio_tungstenite::tungstenite::handshake::client::{generate_key, Request};
-use tokio_tungstenite::tungstenite::http::header::USER_AGENT;
-use tokio_tungstenite::tungstenite::http::Uri;
-use tokio_tungstenite::tungstenite::{http, Error, Message};
-use tracing::{debug, error, info, warn};
+    pub fn lobby_count(&self) -> usize {
+        self.store.lobby_count()
+    }
 
-// This wrapper is what you'll use to interact with the singleton
-pub struct LobbyCacheOnce {
-    // Since we are using lazy_static, we need to wrap it in a Mutex and an Option
-    // Mutex ensures that the Singleton can be safely used across threads
-    // Option lets us take it in init() and then replace it with None
-    inner: StdMutex<Option<Arc<LobbyCache>>>,
-    once: Once,
-}
+    pub async fn last_update(&self) -> Option<time::SystemTime> {
+        self.store.last_update().await
+    }
+
+    pub fn history(&self) -> Option<Arc<HistoryStore>> {
+        self.history.clone()
+    }
 
-static AOE2LOBBY_URL: Lazy<Uri> =
-    Lazy::new(|| Uri::from_str("wss://aoe2lobby.com/ws/lobby/").unwrap());
-static AOE2LOBBY_ORIGIN: Lazy<http::HeaderValue> =
-    Lazy::new(|| http::HeaderValue::from_str("https://aoe2lobby.com").unwrap());
+    async fn restore_snapshot(&self, path: &Path) {
+        let snapshot = match Snapshot::read(path) {
+            Ok(snapshot) => snapshot,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                info!("No snapshot found at {}", path.display());
+                return;
+            }
+            Err(e) => {
+                error!("Failed to read snapshot {}: {:?}", path.display(), e);
+                return;
+            }
+        };
 
-impl LobbyCacheOnce {
-    pub fn new() -> Self {
-        LobbyCacheOnce {
-            inner: StdMutex::new(None),
-            once: Once::new(),
+        let age = snapshot.taken_at.elapsed().unwrap_or_default();
+        if age > self.config.snapshot_max_age {
+            info!("Ignoring snapshot taken {} seconds ago", age.as_secs());
+            return;
         }
+
+        info!(
+            "Restored {} lobbies from snapshot taken {} seconds ago",
+            snapshot.lobbies.len(),
+            age.as_secs()
+        );
+        self.store
+            .restore(snapshot.lobbies, snapshot.taken_at)
+            .await;
     }
 
-    pub fn get_instance(&self) -> Arc<LobbyCache> {
-        // We only want to create the instance once
-        self.once.call_once(|| {
-            let singleton = LobbyCache::new(); // Replace with your data
-            *self.inner.lock().unwrap() = Some(Arc::new(singleton));
-        });
+    async fn save_snapshot(&self, path: &Path) {
+        let path = path.to_path_buf();
+        let taken_at = match self.store.last_update().await {
+            Some(last_update) => last_update,
+            None => return,
+        };
+        let snapshot = Snapshot {
+            taken_at,
+            lobbies: self.store.lobbies(),
+        };
+
+        let lobby_count = snapshot.lobbies.len();
+        match tokio::task::spawn_blocking(move || snapshot.write(&path)).await {
+            Ok(Ok(())) => debug!("Saved snapshot with {} lobbies", lobby_count),
+            Ok(Err(e)) => error!("Failed to write snapshot: {:?}", e),
+            Err(e) => error!("Snapshot task failed: {:?}", e),
+        }
+    }
 
-        // Now we know that the singleton has been created, so we can safely unwrap
-        self.inner.lock().unwrap().as_ref().unwrap().clone()
+    async fn snapshot_loop(&self, path: Option<&Path>) {
+        let path = match path {
+            Some(path) => path,
+            None => return futures::future::pending().await,
+        };
+        loop {
+            sleep(self.config.snapshot_interval).await;
+            self.save_snapshot(path).await;
+        }
     }
-}
 
-pub struct LobbyCache {
-    pub lobby_cache: Arc<DashMap<String, Lobby>>,
-    pub last_update: Arc<TokioMutex<Option<time::SystemTime>>>,
-    pub shutdown: Arc<TokioMutex<mpsc::Sender<()>>>,
-    pub running: Arc<AtomicBool>,
-    pub update_broadcast_sender: broadcast::Sender<()>,
-}
+    async fn history_loop(&self, mut receiver: broadcast::Receiver<LobbyEvent>) {
+        let history = match &self.history {
+            Some(history) => history,
+            None => return futures::future::pending().await,
+        };
 
-impl LobbyCache {
-    pub fn new() -> Self {
-        let (shutdown, _) = mpsc::channel(3);
-        let update_broadcast_sender = broadcast::channel(32).0;
+        // Events only say which lobbies changed, entries are derived from the store itself so
+        // replaying an event after a resync records nothing twice
+        let mut known: HashMap<String, Arc<Lobby>> = self.store.lobbies();
+        loop {
+            let lobby_ids: Vec<String> = match receiver.recv().await {
+                Ok(LobbyEvent::Created(lobby))
+                | Ok(LobbyEvent::Updated { current: lobby, .. })
+                | Ok(LobbyEvent::Deleted { lobby, .. }) => vec![lobby.lobbyid.to_string()],
+                Ok(LobbyEvent::Reset) | Err(broadcast::error::RecvError::Lagged(_)) => known
+                    .keys()
+                    .cloned()
+                    .chain(
+                        self.store
+                            .list()
+                            .iter()
+                            .map(|lobby| lobby.lobbyid.to_string()),
+                    )
+                    .collect(),
+                Err(broadcast::error::RecvError::Closed) => {
+                    return futures::future::pending().await
+                }
+            };
 
-        LobbyCache {
-            last_update: Arc::new(TokioMutex::new(None)),
-            lobby_cache: Arc::new(DashMap::new()),
-            shutdown: Arc::new(TokioMutex::new(shutdown)),
-            running: Arc::new(AtomicBool::new(false)),
-            update_broadcast_sender,
+            let now = time::SystemTime::now();
+            let mut entries = vec![];
+            for lobby_id in lobby_ids {
+                let current = self.store.get(&lobby_id);
+                let previous = match &current {
+                    Some(current) => known.insert(lobby_id, current.clone()),
+                    None => known.remove(&lobby_id),
+                };
+                entries.extend(history::diff(previous.as_deref(), current.as_deref(), now));
+            }
+            history.record(entries).await;
         }
     }
 
-    pub fn subscribe(&self) -> broadcast::Receiver<()> {
-        self.update_broadcast_sender.subscribe()
+    // Runs until the token is cancelled
+    pub async fn run(&self, cancel: CancellationToken) {
+        self.run_sources(source::from_config(&self.config), cancel)
+            .await;
     }
 
-    async fn handle_lobby_update(
-        &self,
-        overwrite_lobbies: HashMap<String, Lobby>,
-        delete_lobbies: Vec<i64>,
-        reset: bool,
-    ) {
-        let mut queue_broadcaster = false;
-        if !overwrite_lobbies.is_empty() || !delete_lobbies.is_empty() || reset {
-            debug!("Sending update broadcast");
-            queue_broadcaster = true;
+    // Serves lobbies from the most preferred source that is synchronized, sources are ordered
+    // from most to least preferred
+    pub async fn run_sources(&self, sources: Vec<Box<dyn LobbySource>>, cancel: CancellationToken) {
+        // A replay must not overwrite the snapshot of the live feed
+        let snapshot_path = match &self.config.replay_path {
+            Some(_) => None,
+            None => self.config.snapshot_path.clone(),
+        };
+        if let Some(snapshot_path) = &snapshot_path {
+            self.restore_snapshot(snapshot_path).await;
         }
 
-        if reset {
-            self.lobby_cache.clear();
+        let history_receiver = self.store.subscribe();
+        tokio::select! {
+            _ = self.source_loop(sources, &cancel) => {}
+            _ = self.snapshot_loop(snapshot_path.as_deref()) => {}
+            _ = self.history_loop(history_receiver) => {}
         }
-        for (lobby_id, lobby) in overwrite_lobbies {
-            self.lobby_cache.insert(lobby_id, lobby);
-        }
-        for lobby_id in delete_lobbies {
-            self.lobby_cache.remove(&lobby_id.to_string());
+        if let Some(snapshot_path) = &snapshot_path {
+            self.save_snapshot(snapshot_path).await;
         }
+    }
 
-        if queue_broadcaster {
-            let _ = self.update_broadcast_sender.send(());
+    async fn source_loop(&self, sources: Vec<Box<dyn LobbySource>>, cancel: &CancellationToken) {
+        // Every source is polled from its own task, so a slow source never delays the others
+        let (update_sender, mut update_receiver) = mpsc::channel(16);
+        let mut states = vec![];
+        let mut tasks = vec![];
+        for (index, mut source) in sources.into_iter().enumerate() {
+            info!("Starting lobby source {}", source.name());
+            states.push(SourceState::new(source.name()));
+            let update_sender = update_sender.clone();
+            tasks.push(tokio::spawn(async move {
+                loop {
+                    let update = source.next_update().await;
+                    let finished = update.is_none();
+                    if update_sender.send((index, update)).await.is_err() || finished {
+                        break;
+                    }
+                }
+            }));
+        }
+        drop(update_sender);
+        defer! {
+            for task in &tasks {
+                task.abort();
+            }
         }
 
-        self.last_update
-            .lock()
-            .await
-            .replace(time::SystemTime::now());
+        let mut selection = SourceSelection {
+            states,
+            active: None,
+            served: None,
+        };
+        self.update_connection_state(&selection);
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => {
+                    warn!("Shutdown received, stopping lobby sources");
+                    return;
+                }
+                received = update_receiver.recv() => {
+                    match received {
+                        Some((index, Some(update))) => {
+                            self.apply_source_update(&mut selection, index, update).await;
+                        }
+                        Some((index, None)) => {
+                            info!("Lobby source {} finished", selection.states[index].name);
+                        }
+                        None => {
+                            // Keep serving the last known lobbies until shutdown
+                            info!("All lobby sources finished");
+                            cancel.cancelled().await;
+                            return;
+                        }
+                    }
+                }
+            }
+        }
     }
 
-    async fn handle_message(&self, msg: Message) -> error::Result<()> {
-        if msg.is_text() || msg.is_binary() {
-            match serde_json::from_str::<WebsocketMessageReceiveAllCurrentLobbies>(
-                msg.to_string().as_str(),
-            ) {
-                Ok(all_current_lobbies) => {
-                    self.handle_lobby_update(all_current_lobbies.allcurrentlobbies, vec![], true)
-                        .await;
-                }
-                Err(e1) => {
-                    match serde_json::from_str::<WebsocketMessageReceiveFollowUp>(
-                        msg.to_string().as_str(),
-                    ) {
-                        Ok(followup) => {
-                            self.handle_lobby_update(
-                                followup.updatedlobbies,
-                                followup.deletedlobbies,
-                                false,
-                            )
-                            .await;
+    async fn apply_source_update(
+        &self,
+        selection: &mut SourceSelection,
+        index: usize,
+        update: SourceUpdate,
+    ) {
+        let states = &mut selection.states;
+        match update {
+            SourceUpdate::Connected => {
+                debug!("Lobby source {} connected", states[index].name);
+                states[index].connected = true;
+            }
+            SourceUpdate::Disconnected(status) => {
+                let state = &mut states[index];
+                state.connected = false;
+                state.synced = false;
+                state.shadow = None;
+                state.status = status;
+
+                if selection.active == Some(index) {
+                    selection.active = states.iter().position(|state| state.synced);
+                    match selection.active {
+                        Some(fallback) => {
+                            warn!(
+                                "Lobby source {} disconnected, failing over to {}",
+                                states[index].name, states[fallback].name
+                            );
+                            let lobbies = states[fallback].shadow.take().unwrap_or_default();
+                            selection.served = Some(fallback);
+                            self.store.apply(lobbies, vec![], true).await;
                         }
-                        Err(e2) => {
-                            warn!("Received unknown message");
-
-                            return Err(error::LobbyCacheError::Parsing(
-                                error::MessageParsingError {
-                                    message: msg.to_string(),
-                                    error_parsing_all_messages: e1,
-                                    error_parsing_followup_message: e2,
-                                },
-                            ));
+                        None => {
+                            warn!(
+                                "Lobby source {} disconnected, no other source is synchronized",
+                                states[index].name
+                            );
                         }
                     }
                 }
-            };
+            }
+            SourceUpdate::Snapshot {
+                mut lobbies,
+                dropped,
+            } => {
+                states[index].synced = true;
+                states[index].status = ReconnectStatus::default();
+                // A more preferred source is being served
+                let stays_shadow = matches!(selection.active, Some(current) if current < index);
+                // Dropped lobbies are still open upstream, deleting them would finalize their
+                // trackers. Their last known state is kept instead, from the cache when the
+                // snapshot is about to be served, even if another source filled it
+                for lobby_id in dropped {
+                    let previous = if stays_shadow {
+                        states[index]
+                            .shadow
+                            .as_ref()
+                            .and_then(|shadow| shadow.get(&lobby_id).cloned())
+                    } else {
+                        self.store.get(&lobby_id)
+                    };
+                    if let Some(previous) = previous {
+                        lobbies.insert(lobby_id, previous);
+                    }
+                }
+                if stays_shadow {
+                    states[index].shadow = Some(lobbies);
+                } else {
+                    if let Some(current) = selection.active.filter(|current| *current != index) {
+                        info!(
+                            "Switching lobby source from {} to {}",
+                            states[current].name, states[index].name
+                        );
+                        states[current].shadow = Some(self.store.lobbies());
+                    }
+                    if selection.served == Some(index) {
+                        self.measure_drift(&states[index].name, &lobbies);
+                    }
+                    selection.active = Some(index);
+                    selection.served = Some(index);
+                    self.store.apply(lobbies, vec![], true).await;
+                }
+            }
+            // Dropped lobbies keep their cached state, the source resyncs to correct it
+            SourceUpdate::Delta {
+                updated, deleted, ..
+            } => {
+                if selection.active == Some(index) {
+                    self.store.apply(updated, deleted, false).await;
+                } else if let Some(shadow) = &mut states[index].shadow {
+                    shadow.extend(updated);
+                    for lobby_id in deleted {
+                        shadow.remove(&lobby_id.to_string());
+                    }
+                }
+            }
         }
 
-        Ok(())
+        self.update_connection_state(selection);
     }
 
-    pub async fn run(&self) {
-        self.running.store(true, Ordering::SeqCst);
-        let (tx, mut rx) = mpsc::channel::<()>(3);
-        *self.shutdown.lock().await = tx;
+    fn update_connection_state(&self, selection: &SourceSelection) {
+        let state = match selection.active {
+            Some(0) => ConnectionState::Live,
+            Some(index) => ConnectionState::Degraded {
+                reason: format!("serving lobbies from {}", selection.states[index].name),
+            },
+            None if self.store.is_provisional() => ConnectionState::Degraded {
+                reason: "serving lobbies restored from a snapshot".to_string(),
+            },
+            None if selection.states.iter().any(|state| state.connected) => {
+                ConnectionState::Synchronizing
+            }
+            None => match selection.states.first() {
+                Some(state) if state.status.next_retry.is_some() => {
+                    ConnectionState::Disconnected(state.status.clone())
+                }
+                _ => ConnectionState::Connecting,
+            },
+        };
+        self.connection_state.send_if_modified(|current| {
+            if *current == state {
+                return false;
+            }
+            info!("Lobby cache is {}", state);
+            *current = state;
+            true
+        });
+    }
 
-        let map_ref_moved = self.lobby_cache.clone();
-        let update_broadcast_sender_moved = self.update_broadcast_sender.clone();
+    // Compares the lobbies built from a source's deltas with its next full snapshot
+    fn measure_drift(&self, source: &str, lobbies: &HashMap<String, Arc<Lobby>>) {
+        let cached = self.store.lobbies();
+        let changed = cached
+            .iter()
+            .filter(|(lobby_id, lobby)| lobbies.get(*lobby_id) != Some(*lobby))
+            .count();
+        let missing = lobbies
+            .keys()
+            .filter(|lobby_id| !cached.contains_key(*lobby_id))
+            .count();
+        let drift = changed + missing;
+        metrics::SNAPSHOT_DRIFT.set(drift as i64);
+        if drift > 0 {
+            warn!(
+                "Lobby source {} snapshot differs from the cached lobbies in {} lobbies",
+                source, drift
+            );
+        }
+    }
+}
 
-        let last_update_moved = self.last_update.clone();
+#[async_trait]
+impl LobbyStore for LobbyCache {
+    fn get(&self, lobby_id: &str) -> Option<Arc<Lobby>> {
+        self.store.get(lobby_id)
+    }
 
-        info!("Starting websocket connection loop");
-        loop {
-            let ws_stream = loop {
-                info!("Connecting to websocket...");
-
-                // https://docs.rs/tungstenite/latest/src/tungstenite/client.rs.html#219
-                let uri = AOE2LOBBY_URL.clone();
-                let authority = uri
-                    .authority()
-                    .ok_or(Error::Url(UrlError::NoHostName))
-                    .unwrap()
-                    .as_str();
-                let host = authority
-                    .find('@')
-                    .map(|idx| authority.split_at(idx + 1).1)
-                    .unwrap_or_else(|| authority);
-
-                let req = Request::builder()
-                    .method("GET")
-                    .header("Host", host)
-                    .header("Connection", "Upgrade")
-                    .header("Upgrade", "websocket")
-                    .header("Sec-WebSocket-Version", "13")
-                    .header("Sec-WebSocket-Key", generate_key())
-                    .header(
-                        "Sec-WebSocket-Extensions",
-                        "permessage-deflate; client_max_window_bits",
-                    )
-                    .header("Origin", AOE2LOBBY_ORIGIN.clone())
-                    .header(
-                        USER_AGENT,
-                        "Lobby-is-up-bot;Repo:https://github.com/L1ghtman2k/lobby-is-up",
-                    )
-                    .uri(uri)
-                    .body(())
-                    .unwrap();
+    fn list(&self) -> Vec<Arc<Lobby>> {
+        self.store.list()
+    }
 
-                match connect_async(req).await {
-                    Ok((ws_stream, _)) => {
-                        break ws_stream;
-                    }
-                    Err(e) => {
-                        error!(
-                            "Error connecting to websocket, retrying in 5 seconds. Error: {:?}",
-                            e
-                        );
+    fn search(&self, query: &LobbyQuery) -> Vec<Arc<Lobby>> {
+        self.store.search(query)
+    }
 
-                        tokio::select! {
-                            _ = rx.recv() => {
-                                warn!("Shutdown received, shutting down websocket connection loop");
-                                return;
-                            }
-                            _ = sleep(Duration::from_secs(7)) => {
-                                warn!("Reconnecting to websocket after 7 seconds of waiting");
-                                continue;
-                            }
-                        }
-                    }
-                }
-            };
-            info!("WebSocket handshake has been successfully completed");
+    fn watch(&self, lobby_id: &str) -> watch::Receiver<WatchedLobby> {
+        self.store.watch(lobby_id)
+    }
 
-            let (mut _write, mut read) = ws_stream.split();
+    fn subscribe(&self) -> broadcast::Receiver<LobbyEvent> {
+        self.store.subscribe()
+    }
 
-            let _update_broadcast_sender_cloned = update_broadcast_sender_moved.clone();
-            let _map_ref = map_ref_moved.clone();
-            let _last_update_clone = last_update_moved.clone();
+    async fn is_fresh(&self) -> bool {
+        self.store.is_fresh().await
+    }
 
-            debug!("Starting websocket reader");
-            loop {
-                tokio::select! {
-                    _ = rx.recv() => {
-                        warn!("Shutdown received, shutting down websocket reader");
-                        return;
-                    }
-                    message = read.next() => {
-                        match message {
-                            Some(Ok(msg)) => {
-                                if let Err(e) = self.handle_message(
-                                    msg,
-                                )
-                                    .await
-                                {
-                                    error!("Error handling message: {:?}", e);
-                                }
-                            }
-                            Some(Err(e)) => {
-                                let e = Arc::new(e);
-                                error!("Error reading message: {:?}. Reconnecting...", e);
-                            }
-                            None => {
-                                warn!("Websocket reader channel closed. Restarting connection...");
-                                break;
-                            }
-                        }
-                    }
+    fn is_provisional(&self) -> bool {
+        self.store.is_provisional()
+    }
 
-                    _ = sleep(Duration::from_secs(20)) => {
-                        warn!("Didn't receive any messages for 20 seconds. Reconecting...");
-                        break;
-                    }
+    fn connection_state(&self) -> ConnectionState {
+        self.connection_state.borrow().clone()
+    }
 
-                }
-            }
+    fn watch_connection_state(&self) -> watch::Receiver<ConnectionState> {
+        self.connection_state.subscribe()
+    }
+}
 
-            tokio::select! {
-                _ = rx.recv() => {
-                    warn!("Shutdown received, shutting down websocket connection loop");
-                    return;
-                }
-                _ = sleep(Duration::from_secs(5)) => {
-                    warn!("Reconnecting to websocket after 5 seconds of waiting");
-                    continue;
+struct SourceSelection {
+    // Ordered from most to least preferred
+    states: Vec<SourceState>,
+    // The synchronized source being served
+    active: Option<usize>,
+    // The source the cached lobbies came from, kept after it disconnects until they are replaced
+    served: Option<usize>,
+}
+
+struct SourceState {
+    name: String,
+    connected: bool,
+    // Whether the source delivered a snapshot since it last connected
+    synced: bool,
+    // Lobbies of a synchronized source that isn't being served, kept to fail over to it
+    shadow: Option<HashMap<String, Arc<Lobby>>>,
+    status: ReconnectStatus,
+}
+
+impl SourceState {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            connected: false,
+            synced: false,
+            shadow: None,
+            status: ReconnectStatus::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lobby_cache::reconnect::CircuitState;
+    use crate::lobby_cache::source::fake::{FakeSource, FakeSourceHandle};
+    use crate::mock_upstream::{self, Step};
+    use serde_json::{json, Value};
+    use tokio::net::TcpListener;
+    use tokio::time::{timeout, Duration};
+
+    const LOBBY_ID: &str = "230389981";
+
+    fn lobby(slotstaken: i64) -> Value {
+        let slot: serde_json::Map<String, Value> = (1..=slotstaken)
+            .map(|slot| {
+                let player = json!({"color": slot.to_string(), "team": "1", "civ": "Franks", "name": format!("Player {}", slot)});
+                (slot.to_string(), player)
+            })
+            .collect();
+        json!({
+            "maxplayers": 8,
+            "relayserver_region": "westeurope",
+            "lobbyid": 230389981,
+            "description": "2v2 arabia",
+            "slotstaken": slotstaken,
+            "slotstotal": 4,
+            "slot": slot,
+        })
+    }
+
+    fn snapshot(lobbies: &[Value]) -> Step {
+        let lobbies: serde_json::Map<String, Value> = lobbies
+            .iter()
+            .map(|lobby| (lobby["lobbyid"].to_string(), lobby.clone()))
+            .collect();
+        Step::Send(json!({ "allcurrentlobbies": lobbies }))
+    }
+
+    fn followup(lobby: Value) -> Step {
+        Step::Send(json!({
+            "deletedlobbies": [],
+            "updatedlobbies": { LOBBY_ID: lobby },
+        }))
+    }
+
+    fn config() -> LobbyCacheConfig {
+        LobbyCacheConfig {
+            reconnect_min_delay: Duration::from_millis(50),
+            reconnect_max_delay: Duration::from_millis(200),
+            reconnect_jitter: 0.0,
+            idle_timeout: Duration::from_secs(1),
+            resync_interval: Duration::ZERO,
+            ..LobbyCacheConfig::default()
+        }
+    }
+
+    // Serves the script on a local port and runs a cache against it until the token is cancelled
+    async fn start(script: Vec<Vec<Step>>, mut config: LobbyCacheConfig) -> Arc<LobbyCache> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        config.url = format!("ws://{}", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        tokio::spawn(mock_upstream::run(listener, script));
+
+        let cache = Arc::new(LobbyCache::new(config));
+        let runner = cache.clone();
+        tokio::spawn(async move { runner.run(CancellationToken::new()).await });
+        cache
+    }
+
+    async fn wait_for<T: Clone>(
+        receiver: &mut watch::Receiver<T>,
+        predicate: impl Fn(&T) -> bool,
+    ) -> T {
+        let wait = async {
+            loop {
+                let value = receiver.borrow_and_update().clone();
+                if predicate(&value) {
+                    return value;
                 }
+                receiver.changed().await.unwrap();
             }
+        };
+        timeout(Duration::from_secs(10), wait)
+            .await
+            .expect("timed out waiting for the cache")
+    }
+
+    fn has_slots(watched: &WatchedLobby, slotstaken: i64) -> bool {
+        matches!(watched, WatchedLobby::Active(lobby) if lobby.slotstaken == slotstaken)
+    }
+
+    #[tokio::test]
+    async fn reconnects_and_resets_lobbies_through_the_script() {
+        let script = vec![
+            vec![snapshot(&[lobby(2)]), followup(lobby(3)), Step::Drop],
+            vec![snapshot(&[]), Step::Silence],
+            vec![snapshot(&[lobby(4)]), Step::Silence],
+        ];
+        let cache = start(script, config()).await;
+        let mut watched = cache.watch(LOBBY_ID);
+
+        wait_for(&mut watched, |watched| has_slots(watched, 3)).await;
+        // The second connection's snapshot no longer holds the lobby
+        let deleted = wait_for(&mut watched, |watched| {
+            matches!(watched, WatchedLobby::Deleted { .. })
+        })
+        .await;
+        assert!(matches!(deleted, WatchedLobby::Deleted { lobby, .. } if lobby.slotstaken == 3));
+        // Then it goes silent, and the connection after the idle timeout brings the lobby back
+        wait_for(&mut watched, |watched| has_slots(watched, 4)).await;
+        assert_eq!(cache.connection_state(), ConnectionState::Live);
+    }
+
+    #[tokio::test]
+    async fn lobbies_go_stale_when_upstream_is_silent() {
+        let config = LobbyCacheConfig {
+            idle_timeout: Duration::from_secs(10),
+            stale_after: Duration::from_millis(300),
+            ..config()
+        };
+        let cache = start(vec![vec![snapshot(&[lobby(2)]), Step::Silence]], config).await;
+
+        let mut connection_state = cache.watch_connection_state();
+        wait_for(&mut connection_state, |state| {
+            *state == ConnectionState::Live
+        })
+        .await;
+        assert!(cache.is_fresh().await);
+        assert_eq!(cache.lobby_count(), 1);
+
+        sleep(Duration::from_millis(500)).await;
+        assert!(!cache.is_fresh().await);
+        // Stale lobbies are kept until upstream replaces them
+        assert_eq!(cache.lobby_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn keeps_lobbies_that_stop_parsing_and_resyncs() {
+        let dead_letter_dir =
+            std::env::temp_dir().join(format!("lobby-is-up-test-{}", uuid::Uuid::new_v4()));
+        let config = LobbyCacheConfig {
+            reconnect_min_delay: Duration::from_millis(300),
+            idle_timeout: Duration::from_secs(10),
+            dead_letter_path: Some(dead_letter_dir.clone()),
+            ..config()
+        };
+        let mut broken = lobby(3);
+        broken["slot"]["1"]["color"] = json!(1);
+        let script = vec![
+            vec![snapshot(&[lobby(2)]), snapshot(&[broken]), Step::Silence],
+            vec![snapshot(&[lobby(4)]), Step::Silence],
+        ];
+        let cache = start(script, config).await;
+        let mut watched = cache.watch(LOBBY_ID);
+
+        wait_for(&mut watched, |watched| has_slots(watched, 2)).await;
+        // Only the resync after the dropped lobby reconnects, the upstream is silent otherwise
+        wait_for(&mut watched, |watched| {
+            assert!(
+                !matches!(watched, WatchedLobby::Deleted { .. }),
+                "a lobby that didn't parse was deleted"
+            );
+            has_slots(watched, 4)
+        })
+        .await;
+        let dead_letters = dead_letter::list_dead_letters(&dead_letter_dir).unwrap();
+        assert_eq!(dead_letters.len(), 1);
+        std::fs::remove_dir_all(&dead_letter_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn resyncs_on_schedule_without_leaving_the_live_state() {
+        let config = LobbyCacheConfig {
+            resync_interval: Duration::from_millis(300),
+            idle_timeout: Duration::from_secs(10),
+            ..config()
+        };
+        let script = vec![
+            vec![snapshot(&[lobby(2)]), Step::Silence],
+            vec![snapshot(&[lobby(3)]), Step::Silence],
+        ];
+        let cache = start(script, config).await;
+        let mut connection_state = cache.watch_connection_state();
+        wait_for(&mut connection_state, |state| {
+            *state == ConnectionState::Live
+        })
+        .await;
+
+        let mut watched = cache.watch(LOBBY_ID);
+        let resynced = wait_for(&mut watched, |watched| has_slots(watched, 3));
+        let left_live = wait_for(&mut connection_state, |state| {
+            *state != ConnectionState::Live
+        });
+        tokio::select! {
+            _ = resynced => {}
+            state = left_live => panic!("a scheduled resync made the cache {}", state),
         }
     }
+
+    #[tokio::test]
+    async fn opens_the_circuit_when_every_connection_fails_to_parse() {
+        let config = LobbyCacheConfig {
+            reconnect_min_delay: Duration::from_millis(10),
+            reconnect_max_delay: Duration::from_millis(40),
+            circuit_breaker_threshold: 3,
+            ..config()
+        };
+        let script = vec![vec![Step::SendRaw("not json".to_string()), Step::Silence]];
+        let cache = start(script, config).await;
+
+        let mut connection_state = cache.watch_connection_state();
+        wait_for(&mut connection_state, |state| {
+            matches!(state, ConnectionState::Disconnected(status) if status.circuit == CircuitState::Open)
+        })
+        .await;
+    }
+
+    // Runs a cache over a primary and a fallback source, both fed by the test
+    fn start_sources() -> (Arc<LobbyCache>, FakeSourceHandle, FakeSourceHandle) {
+        let (primary, primary_handle) = FakeSource::new("primary");
+        let (fallback, fallback_handle) = FakeSource::new("fallback");
+        let cache = Arc::new(LobbyCache::new(config()));
+        let runner = cache.clone();
+        tokio::spawn(async move {
+            let sources: Vec<Box<dyn LobbySource>> = vec![Box::new(primary), Box::new(fallback)];
+            runner.run_sources(sources, CancellationToken::new()).await
+        });
+        (cache, primary_handle, fallback_handle)
+    }
+
+    fn source_lobbies(slotstaken: i64) -> HashMap<String, Arc<Lobby>> {
+        let lobby = Lobby {
+            lobbyid: LOBBY_ID.parse().unwrap(),
+            slotstaken,
+            slotstotal: 4,
+            ..Lobby::default()
+        };
+        HashMap::from([(LOBBY_ID.to_string(), Arc::new(lobby))])
+    }
+
+    fn source_snapshot(slotstaken: i64) -> SourceUpdate {
+        SourceUpdate::Snapshot {
+            lobbies: source_lobbies(slotstaken),
+            dropped: vec![],
+        }
+    }
+
+    fn source_delta(slotstaken: i64) -> SourceUpdate {
+        SourceUpdate::Delta {
+            updated: source_lobbies(slotstaken),
+            deleted: vec![],
+            dropped: vec![],
+        }
+    }
+
+    fn disconnected() -> SourceUpdate {
+        SourceUpdate::Disconnected(ReconnectStatus::default())
+    }
+
+    fn serving_fallback(state: &ConnectionState) -> bool {
+        matches!(state, ConnectionState::Degraded { reason } if reason.contains("fallback"))
+    }
+
+    #[tokio::test]
+    async fn fails_over_when_the_primary_disconnects() {
+        let (cache, primary, fallback) = start_sources();
+        let mut watched = cache.watch(LOBBY_ID);
+        let mut connection_state = cache.watch_connection_state();
+
+        primary.send(source_snapshot(2)).await;
+        fallback.send(source_snapshot(3)).await;
+        wait_for(&mut connection_state, |state| {
+            *state == ConnectionState::Live
+        })
+        .await;
+        assert!(has_slots(&watched.borrow(), 2));
+
+        primary.send(disconnected()).await;
+        wait_for(&mut connection_state, serving_fallback).await;
+        wait_for(&mut watched, |watched| has_slots(watched, 3)).await;
+    }
+
+    #[tokio::test]
+    async fn applies_deltas_to_the_shadow_of_the_fallback() {
+        let (cache, primary, fallback) = start_sources();
+        let mut watched = cache.watch(LOBBY_ID);
+
+        primary.send(source_snapshot(2)).await;
+        fallback.send(source_snapshot(2)).await;
+        fallback.send(source_delta(3)).await;
+        primary.send(source_delta(4)).await;
+        // Updates are applied in order, so the fallback's delta was handled before this one
+        wait_for(&mut watched, |watched| {
+            assert!(!has_slots(watched, 3), "a delta of the fallback was served");
+            has_slots(watched, 4)
+        })
+        .await;
+
+        primary.send(disconnected()).await;
+        wait_for(&mut watched, |watched| has_slots(watched, 3)).await;
+    }
+
+    #[tokio::test]
+    async fn switches_back_when_the_primary_resyncs() {
+        let (cache, primary, fallback) = start_sources();
+        let mut watched = cache.watch(LOBBY_ID);
+        let mut connection_state = cache.watch_connection_state();
+
+        primary.send(source_snapshot(2)).await;
+        fallback.send(source_snapshot(3)).await;
+        primary.send(disconnected()).await;
+        wait_for(&mut connection_state, serving_fallback).await;
+
+        primary.send(SourceUpdate::Connected).await;
+        primary.send(source_snapshot(4)).await;
+        wait_for(&mut connection_state, |state| {
+            *state == ConnectionState::Live
+        })
+        .await;
+        wait_for(&mut watched, |watched| has_slots(watched, 4)).await;
+
+        // The fallback is shadowed again, from the lobbies it served
+        fallback.send(source_delta(5)).await;
+        primary.send(disconnected()).await;
+        wait_for(&mut connection_state, serving_fallback).await;
+        wait_for(&mut watched, |watched| has_slots(watched, 5)).await;
+    }
+
+    #[tokio::test]
+    async fn keeps_dropped_lobbies_when_the_primary_takes_over() {
+        let (cache, primary, fallback) = start_sources();
+        let mut watched = cache.watch(LOBBY_ID);
+        let mut connection_state = cache.watch_connection_state();
+
+        primary.send(source_snapshot(2)).await;
+        fallback.send(source_snapshot(3)).await;
+        primary.send(disconnected()).await;
+        wait_for(&mut watched, |watched| has_slots(watched, 3)).await;
+
+        // The primary lost its shadow on disconnect, the lobby is kept from the fallback
+        primary
+            .send(SourceUpdate::Snapshot {
+                lobbies: HashMap::new(),
+                dropped: vec![LOBBY_ID.to_string()],
+            })
+            .await;
+        wait_for(&mut connection_state, |state| {
+            *state == ConnectionState::Live
+        })
+        .await;
+        assert!(has_slots(&watched.borrow(), 3));
+    }
 }
@@ -1,33 +1,299 @@
+use crate::lobby_cache::intern::Symbol;
+use crate::metrics;
+use serde::de::{DeserializeOwned, Deserializer};
+use serde::Deserialize as _;
 use serde_derive::Deserialize;
 use serde_derive::Serialize;
+use serde_json::value::RawValue;
+use serde_json::Value;
 use std::collections::HashMap;
+use std::sync::Arc;
+use tracing::warn;
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct WebsocketMessageReceiveAllCurrentLobbies {
-    pub allcurrentlobbies: HashMap<String, Lobby>,
+    pub allcurrentlobbies: HashMap<String, Arc<Lobby>>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct WebsocketMessageReceiveFollowUp {
     pub deletedlobbies: Vec<i64>,
-    pub updatedlobbies: HashMap<String, Lobby>,
+    pub updatedlobbies: HashMap<String, Arc<Lobby>>,
+}
+
+// Every frame upstream may send
+#[derive(Debug, Clone, PartialEq)]
+pub enum UpstreamFrame {
+    AllCurrentLobbies(WebsocketMessageReceiveAllCurrentLobbies),
+    FollowUp(WebsocketMessageReceiveFollowUp),
+    // Valid json that isn't one of the known frames, including json that isn't an object. Only
+    // built into a json tree once the frame is known to hold no lobbies
+    Unknown(Value),
+}
+
+// The keys of every known frame, deserialized straight from the message without building a json
+// tree first. Keys of other frames are skipped. Lobbies are only delimited here, and parsed one
+// by one afterwards
+#[derive(Deserialize)]
+struct RawFrame<'a> {
+    #[serde(borrow)]
+    allcurrentlobbies: Option<HashMap<String, &'a RawValue>>,
+    #[serde(borrow)]
+    updatedlobbies: Option<HashMap<String, &'a RawValue>>,
+    deletedlobbies: Option<Vec<i64>>,
+}
+
+// A frame along with the lobbies that were left out of it
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParsedFrame {
+    pub frame: UpstreamFrame,
+    // Ids of the lobbies sent in the frame that didn't parse
+    pub dropped_lobbies: Vec<String>,
+}
+
+impl UpstreamFrame {
+    pub fn parse(data: &[u8]) -> serde_json::Result<ParsedFrame> {
+        let frame: RawFrame = match serde_json::from_slice(data) {
+            Ok(frame) => frame,
+            // Only objects can be known frames, anything else is an unknown frame if it's json
+            Err(e) if !is_object(data) => {
+                return Ok(ParsedFrame {
+                    frame: UpstreamFrame::Unknown(serde_json::from_slice(data).map_err(|_| e)?),
+                    dropped_lobbies: vec![],
+                })
+            }
+            Err(e) => return Err(e),
+        };
+        let mut dropped_lobbies = vec![];
+        let frame = if let Some(allcurrentlobbies) = frame.allcurrentlobbies {
+            UpstreamFrame::AllCurrentLobbies(WebsocketMessageReceiveAllCurrentLobbies {
+                allcurrentlobbies: parse_lobbies(allcurrentlobbies, &mut dropped_lobbies),
+            })
+        } else if frame.updatedlobbies.is_some() || frame.deletedlobbies.is_some() {
+            UpstreamFrame::FollowUp(WebsocketMessageReceiveFollowUp {
+                deletedlobbies: frame.deletedlobbies.unwrap_or_default(),
+                updatedlobbies: frame
+                    .updatedlobbies
+                    .map(|lobbies| parse_lobbies(lobbies, &mut dropped_lobbies))
+                    .unwrap_or_default(),
+            })
+        } else {
+            UpstreamFrame::Unknown(serde_json::from_slice(data)?)
+        };
+        Ok(ParsedFrame {
+            frame,
+            dropped_lobbies,
+        })
+    }
+
+    // Labels the frame in metrics, unknown frames by their top-level key
+    pub fn kind(&self) -> &str {
+        match self {
+            UpstreamFrame::AllCurrentLobbies(_) => "full",
+            UpstreamFrame::FollowUp(_) => "followup",
+            UpstreamFrame::Unknown(Value::Object(frame)) => {
+                frame.keys().next().map_or("unknown", String::as_str)
+            }
+            UpstreamFrame::Unknown(_) => "unknown",
+        }
+    }
+}
+
+fn is_object(data: &[u8]) -> bool {
+    matches!(
+        data.iter().find(|byte| !byte.is_ascii_whitespace()),
+        Some(b'{')
+    )
+}
+
+// A lobby that doesn't parse is left out and reported in dropped, instead of failing the whole
+// frame with it
+fn parse_lobbies(
+    lobbies: HashMap<String, &RawValue>,
+    dropped: &mut Vec<String>,
+) -> HashMap<String, Arc<Lobby>> {
+    let mut parsed = HashMap::with_capacity(lobbies.len());
+    for (lobby_id, lobby) in lobbies {
+        match serde_json::from_str::<Lobby>(lobby.get()) {
+            Ok(lobby) => {
+                parsed.insert(lobby_id, Arc::new(lobby));
+            }
+            Err(e) => {
+                warn!("Dropping lobby {} that doesn't parse: {}", lobby_id, e);
+                metrics::DROPPED_LOBBIES.inc();
+                dropped.push(lobby_id);
+            }
+        }
+    }
+    parsed
+}
+
+// Optional fields fall back to None when upstream sends them with another type
+fn lenient<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: DeserializeOwned,
+{
+    let value = Value::deserialize(deserializer)?;
+    Ok(T::deserialize(value).ok())
+}
+
+// Flags also come as 0 and 1
+fn lenient_bool<'de, D>(deserializer: D) -> Result<Option<bool>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(match Value::deserialize(deserializer)? {
+        Value::Bool(flag) => Some(flag),
+        Value::Number(number) => number.as_i64().map(|number| number != 0),
+        _ => None,
+    })
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Lobby {
     pub maxplayers: i64,
-    pub relayserver_region: String,
+    pub relayserver_region: Symbol,
     pub lobbyid: i64,
     pub description: String,
     pub slotstaken: i64,
     pub slotstotal: i64,
     pub slot: HashMap<String, Slot>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub map: Option<String>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub gametype: Option<String>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub speed: Option<String>,
+    #[serde(default, deserialize_with = "lenient_bool")]
+    pub passwordprotected: Option<bool>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub host: Option<String>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub hostprofileid: Option<i64>,
+    #[serde(default, deserialize_with = "lenient_bool")]
+    pub spectatable: Option<bool>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub spectatorcount: Option<i64>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub spectatordelay: Option<i64>,
+    // Fields upstream sends that aren't modelled above. Flattening makes serde buffer every field
+    // of the lobby before deserializing it, which is why slots don't keep theirs
+    #[serde(flatten)]
+    pub extra: HashMap<String, Value>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Slot {
-    pub color: String,
-    pub team: Option<String>,
-    pub civ: String,
+    pub color: Symbol,
+    pub team: Option<Symbol>,
+    pub civ: Symbol,
     pub name: Option<String>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub playerid: Option<i64>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub profileid: Option<i64>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub rating: Option<i64>,
+    #[serde(default, deserialize_with = "lenient_bool")]
+    pub spectator: Option<bool>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOBBY: &str = r#"{"maxplayers": 8, "relayserver_region": "westeurope", "lobbyid": 1, "description": "2v2 arabia", "slotstaken": 1, "slotstotal": 4, "slot": {"1": {"color": "1", "team": "1", "civ": "Franks", "name": "Alice"}}}"#;
+
+    #[test]
+    fn parses_a_full_snapshot() {
+        let frame = format!(r#"{{"allcurrentlobbies": {{"1": {}}}}}"#, LOBBY);
+        match UpstreamFrame::parse(frame.as_bytes()).unwrap().frame {
+            UpstreamFrame::AllCurrentLobbies(frame) => {
+                let lobby = &frame.allcurrentlobbies["1"];
+                assert_eq!(lobby.description, "2v2 arabia");
+                assert_eq!(lobby.slot["1"].name.as_deref(), Some("Alice"));
+            }
+            frame => panic!("unexpected frame {:?}", frame),
+        }
+    }
+
+    #[test]
+    fn parses_a_followup_with_only_deletions() {
+        let frame = UpstreamFrame::parse(br#"{"deletedlobbies": [1, 2]}"#)
+            .unwrap()
+            .frame;
+        assert_eq!(
+            frame,
+            UpstreamFrame::FollowUp(WebsocketMessageReceiveFollowUp {
+                deletedlobbies: vec![1, 2],
+                updatedlobbies: HashMap::new(),
+            })
+        );
+    }
+
+    #[test]
+    fn treats_other_json_as_unknown_frames() {
+        for data in [
+            r#"{"onlineplayers": 1234}"#,
+            r#""ping""#,
+            "[]",
+            "1",
+            " null ",
+        ] {
+            let frame = UpstreamFrame::parse(data.as_bytes()).unwrap().frame;
+            assert_eq!(
+                frame,
+                UpstreamFrame::Unknown(serde_json::from_str(data).unwrap()),
+                "{}",
+                data
+            );
+        }
+    }
+
+    #[test]
+    fn labels_unknown_frames_by_their_key() {
+        let frame = UpstreamFrame::parse(br#"{"onlineplayers": 1234}"#)
+            .unwrap()
+            .frame;
+        assert_eq!(frame.kind(), "onlineplayers");
+        let frame = UpstreamFrame::parse(br#""ping""#).unwrap().frame;
+        assert_eq!(frame.kind(), "unknown");
+    }
+
+    #[test]
+    fn fails_on_invalid_json_and_mistyped_known_frames() {
+        for data in ["not a lobby frame", r#"{"deletedlobbies": "1"}"#, r#"["#] {
+            assert!(UpstreamFrame::parse(data.as_bytes()).is_err(), "{}", data);
+        }
+    }
+
+    #[test]
+    fn reports_lobbies_that_dont_parse() {
+        let broken = LOBBY.replace(r#""color": "1""#, r#""color": 1"#);
+        let frame = format!(
+            r#"{{"deletedlobbies": [], "updatedlobbies": {{"1": {}, "2": {}}}}}"#,
+            LOBBY,
+            broken.replace(r#""lobbyid": 1"#, r#""lobbyid": 2"#)
+        );
+        let parsed = UpstreamFrame::parse(frame.as_bytes()).unwrap();
+        assert_eq!(parsed.dropped_lobbies, vec!["2".to_string()]);
+        match parsed.frame {
+            UpstreamFrame::FollowUp(frame) => {
+                assert_eq!(frame.updatedlobbies.keys().collect::<Vec<_>>(), vec!["1"]);
+            }
+            frame => panic!("unexpected frame {:?}", frame),
+        }
+    }
+
+    #[test]
+    fn keeps_unknown_lobby_fields_and_tolerates_mistyped_optional_ones() {
+        let lobby: Lobby = serde_json::from_str(
+            r#"{"maxplayers": 8, "relayserver_region": "westeurope", "lobbyid": 1, "description": "", "slotstaken": 0, "slotstotal": 4, "slot": {}, "spectatorcount": "many", "passwordprotected": 1, "newfield": true}"#,
+        )
+        .unwrap();
+        assert_eq!(lobby.spectatorcount, None);
+        assert_eq!(lobby.passwordprotected, Some(true));
+        assert_eq!(lobby.extra["newfield"], Value::Bool(true));
+    }
 }
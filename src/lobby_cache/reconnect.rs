@@ -0,0 +1,156 @@
+use rand::Rng;
+use std::time::SystemTime;
+use tokio::time::Duration;
+
+pub trait ReconnectPolicy: Send + Sync {
+    // Records a failed connection attempt or a lost connection, and returns how long to wait
+    // before the next attempt
+    fn next_delay(&mut self) -> Duration;
+
+    // Records that the connection is healthy again
+    fn reset(&mut self);
+
+    fn circuit(&self) -> CircuitState;
+
+    fn failures(&self) -> u32;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    // Upstream is reachable, or only failed a few times in a row
+    Closed,
+    // Upstream failed too many times in a row and is considered down
+    Open,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReconnectStatus {
+    pub circuit: CircuitState,
+    pub failures: u32,
+    // When the next connection attempt is scheduled, if one is pending
+    pub next_retry: Option<SystemTime>,
+    // Why the last connection was lost or couldn't be established
+    pub last_error: Option<String>,
+}
+
+impl Default for ReconnectStatus {
+    fn default() -> Self {
+        Self {
+            circuit: CircuitState::Closed,
+            failures: 0,
+            next_retry: None,
+            last_error: None,
+        }
+    }
+}
+
+// Doubles the delay after every consecutive failure up to max_delay, and randomly shortens each
+// delay by up to the jitter fraction, so restarts don't reconnect in lockstep
+#[derive(Debug, Clone)]
+pub struct ExponentialBackoff {
+    pub min_delay: Duration,
+    pub max_delay: Duration,
+    pub jitter: f64,
+    // Consecutive failures after which the circuit opens
+    pub failure_threshold: u32,
+    failures: u32,
+}
+
+impl ExponentialBackoff {
+    pub fn new(
+        min_delay: Duration,
+        max_delay: Duration,
+        jitter: f64,
+        failure_threshold: u32,
+    ) -> Self {
+        Self {
+            min_delay,
+            max_delay,
+            jitter: jitter.clamp(0.0, 1.0),
+            failure_threshold,
+            failures: 0,
+        }
+    }
+}
+
+impl ReconnectPolicy for ExponentialBackoff {
+    fn next_delay(&mut self) -> Duration {
+        let exponent = self.failures.min(16) as i32;
+        self.failures = self.failures.saturating_add(1);
+
+        let delay = self
+            .min_delay
+            .mul_f64(2f64.powi(exponent))
+            .min(self.max_delay);
+        if self.jitter > 0.0 {
+            delay.mul_f64(1.0 - rand::thread_rng().gen_range(0.0..=self.jitter))
+        } else {
+            delay
+        }
+    }
+
+    fn reset(&mut self) {
+        self.failures = 0;
+    }
+
+    fn circuit(&self) -> CircuitState {
+        if self.failures >= self.failure_threshold {
+            CircuitState::Open
+        } else {
+            CircuitState::Closed
+        }
+    }
+
+    fn failures(&self) -> u32 {
+        self.failures
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backoff(jitter: f64) -> ExponentialBackoff {
+        ExponentialBackoff::new(Duration::from_secs(1), Duration::from_secs(10), jitter, 3)
+    }
+
+    #[test]
+    fn doubles_the_delay_up_to_the_maximum() {
+        let mut policy = backoff(0.0);
+        let delays: Vec<u64> = (0..6).map(|_| policy.next_delay().as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 10, 10]);
+    }
+
+    #[test]
+    fn jitter_only_shortens_the_delay() {
+        let mut policy = backoff(0.5);
+        for expected in [1, 2, 4, 8, 10] {
+            let delay = policy.next_delay();
+            let expected = Duration::from_secs(expected);
+            assert!(delay <= expected, "{:?} > {:?}", delay, expected);
+            assert!(delay >= expected / 2, "{:?} < {:?}", delay, expected / 2);
+        }
+    }
+
+    #[test]
+    fn opens_the_circuit_after_consecutive_failures_until_reset() {
+        let mut policy = backoff(0.0);
+        for _ in 0..2 {
+            policy.next_delay();
+        }
+        assert_eq!(policy.circuit(), CircuitState::Closed);
+        policy.next_delay();
+        assert_eq!(policy.circuit(), CircuitState::Open);
+        assert_eq!(policy.failures(), 3);
+
+        policy.reset();
+        assert_eq!(policy.circuit(), CircuitState::Closed);
+        assert_eq!(policy.next_delay(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn clamps_the_jitter_fraction() {
+        assert_eq!(backoff(2.0).jitter, 1.0);
+        assert_eq!(backoff(-1.0).jitter, 0.0);
+    }
+}
@@ -0,0 +1,27 @@
+use crate::lobby_cache::model::Lobby;
+use crate::lobby_cache::util::write_atomically;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    // Time of the last upstream update reflected in the snapshot
+    pub taken_at: SystemTime,
+    pub lobbies: HashMap<String, Arc<Lobby>>,
+}
+
+impl Snapshot {
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let content = fs::read(path)?;
+        Ok(serde_json::from_slice(&content)?)
+    }
+
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        write_atomically(path, &serde_json::to_vec(self)?)
+    }
+}
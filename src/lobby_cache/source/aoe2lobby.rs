@@ -0,0 +1,332 @@
+use crate::lobby_cache::capture::CaptureWriter;
+use crate::lobby_cache::config::LobbyCacheConfig;
+use crate::lobby_cache::dead_letter::DeadLetterWriter;
+use crate::lobby_cache::error::{self as cache_error, MessageParsingError};
+use crate::lobby_cache::model::UpstreamFrame;
+use crate::lobby_cache::reconnect::{ExponentialBackoff, ReconnectPolicy, ReconnectStatus};
+use crate::lobby_cache::source::{LobbySource, SourceUpdate};
+use crate::metrics;
+use futures::stream::SplitStream;
+use serenity::async_trait;
+use serenity::futures::StreamExt;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::net::TcpStream;
+use tokio::time::{sleep_until, timeout, Duration, Instant};
+use tokio_tungstenite::tungstenite::error::UrlError;
+use tokio_tungstenite::tungstenite::handshake::client::{generate_key, Request};
+use tokio_tungstenite::tungstenite::http::header::USER_AGENT;
+use tokio_tungstenite::tungstenite::http::{HeaderValue, Uri};
+use tokio_tungstenite::tungstenite::{Error, Message};
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+use tracing::{debug, error, info, warn};
+
+type WebsocketReader = SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>;
+
+// Lobbies from the aoe2lobby.com websocket, or anything speaking the same protocol
+pub struct Aoe2LobbySource {
+    name: String,
+    url: Uri,
+    origin: HeaderValue,
+    user_agent: String,
+    idle_timeout: Duration,
+    resync_interval: Duration,
+    // When the last full snapshot was received on the current connection
+    synced_at: Option<Instant>,
+    capture: Option<CaptureWriter>,
+    dead_letters: Option<Arc<DeadLetterWriter>>,
+    reconnect_policy: Box<dyn ReconnectPolicy>,
+    reader: Option<WebsocketReader>,
+    // Resync due before reading any further message
+    pending_resync: Option<&'static str>,
+    // Connection attempts wait until this instant, kept across calls so a dropped future
+    // doesn't skip the delay
+    retry_at: Option<Instant>,
+}
+
+impl Aoe2LobbySource {
+    pub fn new(name: &str, url: Uri, config: &LobbyCacheConfig) -> Self {
+        Self {
+            name: name.to_string(),
+            url,
+            origin: config.origin.clone(),
+            user_agent: config.user_agent.clone(),
+            idle_timeout: config.idle_timeout,
+            resync_interval: config.resync_interval,
+            synced_at: None,
+            capture: None,
+            dead_letters: None,
+            reconnect_policy: Box::new(ExponentialBackoff::new(
+                config.reconnect_min_delay,
+                config.reconnect_max_delay,
+                config.reconnect_jitter,
+                config.circuit_breaker_threshold,
+            )),
+            reader: None,
+            pending_resync: None,
+            retry_at: None,
+        }
+    }
+
+    pub fn with_capture(mut self, capture: CaptureWriter) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+
+    pub fn with_dead_letters(mut self, dead_letters: Arc<DeadLetterWriter>) -> Self {
+        self.dead_letters = Some(dead_letters);
+        self
+    }
+
+    async fn connect(&self) -> cache_error::Result<WebsocketReader> {
+        // https://docs.rs/tungstenite/latest/src/tungstenite/client.rs.html#219
+        let uri = self.url.clone();
+        let authority = uri
+            .authority()
+            .ok_or(Error::Url(UrlError::NoHostName))?
+            .as_str();
+        let host = authority
+            .find('@')
+            .map(|idx| authority.split_at(idx + 1).1)
+            .unwrap_or_else(|| authority);
+
+        let req = Request::builder()
+            .method("GET")
+            .header("Host", host)
+            .header("Connection", "Upgrade")
+            .header("Upgrade", "websocket")
+            .header("Sec-WebSocket-Version", "13")
+            .header("Sec-WebSocket-Key", generate_key())
+            .header(
+                "Sec-WebSocket-Extensions",
+                "permessage-deflate; client_max_window_bits",
+            )
+            .header("Origin", self.origin.clone())
+            .header(USER_AGENT, self.user_agent.as_str())
+            .uri(uri)
+            .body(())
+            .map_err(Error::from)?;
+
+        let (ws_stream, _) = connect_async(req).await?;
+        let (_write, read) = ws_stream.split();
+        Ok(read)
+    }
+
+    // Drops the connection and asks the reconnect policy when to try again
+    fn schedule_reconnect(&mut self, error: String) -> SourceUpdate {
+        self.reader = None;
+        self.synced_at = None;
+        self.pending_resync = None;
+        let delay = self.reconnect_policy.next_delay();
+        self.retry_at = Some(Instant::now() + delay);
+        info!(
+            "{}: reconnecting to websocket in {:.1} seconds",
+            self.name,
+            delay.as_secs_f64()
+        );
+        SourceUpdate::Disconnected(ReconnectStatus {
+            circuit: self.reconnect_policy.circuit(),
+            failures: self.reconnect_policy.failures(),
+            next_retry: Some(SystemTime::now() + delay),
+            last_error: Some(error),
+        })
+    }
+
+    // Reconnects to receive a fresh full snapshot, since the deltas applied since the last one
+    // can't be trusted anymore
+    fn resync(&mut self, reason: &str) -> SourceUpdate {
+        metrics::RESYNCS.with_label_values(&[reason]).inc();
+        self.schedule_reconnect(format!(
+            "resynchronizing after {}",
+            reason.replace('_', " ")
+        ))
+    }
+
+    // Time left until the next scheduled resync, None when none is scheduled
+    fn resync_in(&self) -> Option<Duration> {
+        match self.synced_at {
+            Some(synced_at) if !self.resync_interval.is_zero() => {
+                Some(self.resync_interval.saturating_sub(synced_at.elapsed()))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[async_trait]
+impl LobbySource for Aoe2LobbySource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn next_update(&mut self) -> Option<SourceUpdate> {
+        loop {
+            if let Some(reason) = self.pending_resync.take() {
+                return Some(self.resync(reason));
+            }
+            let resync_in = self.resync_in();
+            if resync_in == Some(Duration::ZERO) {
+                info!("{}: resynchronizing on schedule", self.name);
+                metrics::RESYNCS.with_label_values(&["interval"]).inc();
+                // The current connection is only replaced once the new one is open, a planned
+                // resync must not look like an outage
+                match self.connect().await {
+                    Ok(reader) => {
+                        self.reader = Some(reader);
+                        self.synced_at = None;
+                    }
+                    Err(e) => {
+                        warn!(
+                            "{}: error connecting to websocket, keeping the current connection: {:?}",
+                            self.name, e
+                        );
+                        self.synced_at = Some(Instant::now());
+                    }
+                }
+                continue;
+            }
+
+            let reader = match &mut self.reader {
+                Some(reader) => reader,
+                None => {
+                    if let Some(retry_at) = self.retry_at {
+                        sleep_until(retry_at).await;
+                        self.retry_at = None;
+                        metrics::RECONNECTS.inc();
+                    }
+
+                    info!("{}: connecting to websocket...", self.name);
+                    match self.connect().await {
+                        Ok(reader) => {
+                            info!(
+                                "{}: websocket handshake has been successfully completed",
+                                self.name
+                            );
+                            self.reader = Some(reader);
+                            return Some(SourceUpdate::Connected);
+                        }
+                        Err(e) => {
+                            error!("{}: error connecting to websocket: {:?}", self.name, e);
+                            return Some(self.schedule_reconnect(format!("{}", e)));
+                        }
+                    }
+                }
+            };
+
+            // Stop waiting when the resync is due, a silent upstream must not postpone it
+            let wait = match resync_in {
+                Some(resync_in) if resync_in < self.idle_timeout => resync_in,
+                _ => self.idle_timeout,
+            };
+            match timeout(wait, reader.next()).await {
+                Ok(Some(Ok(msg))) => {
+                    if let Some(capture) = &self.capture {
+                        capture.record(&msg);
+                    }
+                    match parse_message(&msg) {
+                        Ok(Some(update)) => {
+                            if let SourceUpdate::Snapshot { .. } = update {
+                                self.synced_at = Some(Instant::now());
+                            }
+                            if update.dropped_lobbies().is_empty() {
+                                // Only lobbies that parse prove the connection is healthy, a
+                                // frame failing on every connection must open the circuit
+                                self.reconnect_policy.reset();
+                            } else {
+                                // The rest of the frame is still applied, the dropped lobbies
+                                // are refreshed by the resync
+                                if let Some(dead_letters) = &self.dead_letters {
+                                    // Json that parsed is valid utf-8
+                                    dead_letters
+                                        .record(&self.name, msg.to_text().unwrap_or_default());
+                                }
+                                self.pending_resync = Some("dropped_lobbies");
+                            }
+                            return Some(update);
+                        }
+                        Ok(None) => {}
+                        Err(e) => {
+                            error!("{}: error handling message: {:?}", self.name, e);
+                            if let Some(dead_letters) = &self.dead_letters {
+                                dead_letters.record(&self.name, &e.message);
+                            }
+                            // A lost delta leaves lobbies wrong until the next full snapshot
+                            return Some(self.resync("parse_failure"));
+                        }
+                    }
+                }
+                Ok(Some(Err(e))) => {
+                    error!("{}: error reading message: {:?}", self.name, e);
+                }
+                Ok(None) => {
+                    warn!("{}: websocket reader channel closed", self.name);
+                    return Some(self.schedule_reconnect("connection closed".to_string()));
+                }
+                Err(_) if wait < self.idle_timeout => {}
+                Err(_) => {
+                    warn!(
+                        "{}: didn't receive any messages for {} seconds",
+                        self.name,
+                        self.idle_timeout.as_secs()
+                    );
+                    return Some(self.schedule_reconnect(format!(
+                        "no messages for {} seconds",
+                        self.idle_timeout.as_secs()
+                    )));
+                }
+            }
+        }
+    }
+}
+
+// Turns a websocket message into a lobby update, control frames and unknown frames yield None
+pub fn parse_message(msg: &Message) -> Result<Option<SourceUpdate>, MessageParsingError> {
+    let data = match msg {
+        Message::Text(text) => text.as_bytes(),
+        Message::Binary(data) => data.as_slice(),
+        Message::Ping(_) | Message::Pong(_) => {
+            metrics::FRAMES_RECEIVED.with_label_values(&["ping"]).inc();
+            debug!("Received ping/pong");
+            return Ok(None);
+        }
+        Message::Close(close_frame) => {
+            metrics::FRAMES_RECEIVED.with_label_values(&["close"]).inc();
+            warn!("Received close frame: {:?}", close_frame);
+            return Ok(None);
+        }
+        Message::Frame(_) => return Ok(None),
+    };
+
+    let parsed = match UpstreamFrame::parse(data) {
+        Ok(parsed) => parsed,
+        Err(error) => {
+            warn!("Received unparseable message");
+            metrics::PARSE_FAILURES.inc();
+
+            return Err(MessageParsingError {
+                message: String::from_utf8_lossy(data).into_owned(),
+                error,
+            });
+        }
+    };
+    metrics::FRAMES_RECEIVED
+        .with_label_values(&[parsed.frame.kind()])
+        .inc();
+    let dropped = parsed.dropped_lobbies;
+    match parsed.frame {
+        UpstreamFrame::AllCurrentLobbies(all_current_lobbies) => Ok(Some(SourceUpdate::Snapshot {
+            lobbies: all_current_lobbies.allcurrentlobbies,
+            dropped,
+        })),
+        UpstreamFrame::FollowUp(followup) => Ok(Some(SourceUpdate::Delta {
+            updated: followup.updatedlobbies,
+            deleted: followup.deletedlobbies,
+            dropped,
+        })),
+        // Upstream also sends frames the cache doesn't need, e.g. onlineplayers
+        UpstreamFrame::Unknown(payload) => {
+            debug!("Received unknown frame: {}", payload);
+            Ok(None)
+        }
+    }
+}
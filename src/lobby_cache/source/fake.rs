@@ -0,0 +1,53 @@
+use crate::lobby_cache::source::{LobbySource, SourceUpdate};
+use serenity::async_trait;
+use tokio::sync::{mpsc, oneshot};
+
+// In-memory source fed by the tests, finishes once its handle is dropped
+pub struct FakeSource {
+    name: String,
+    updates: mpsc::Receiver<(SourceUpdate, oneshot::Sender<()>)>,
+    delivered: Option<oneshot::Sender<()>>,
+}
+
+pub struct FakeSourceHandle {
+    updates: mpsc::Sender<(SourceUpdate, oneshot::Sender<()>)>,
+}
+
+impl FakeSource {
+    pub fn new(name: &str) -> (Self, FakeSourceHandle) {
+        let (sender, receiver) = mpsc::channel(1);
+        let source = Self {
+            name: name.to_string(),
+            updates: receiver,
+            delivered: None,
+        };
+        (source, FakeSourceHandle { updates: sender })
+    }
+}
+
+impl FakeSourceHandle {
+    // Resolves once the update was handed to the cache, so updates sent afterwards by any source
+    // are applied after it
+    pub async fn send(&self, update: SourceUpdate) {
+        let (delivered, handed_over) = oneshot::channel();
+        self.updates.send((update, delivered)).await.unwrap();
+        handed_over.await.unwrap();
+    }
+}
+
+#[async_trait]
+impl LobbySource for FakeSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn next_update(&mut self) -> Option<SourceUpdate> {
+        // The source is only polled again after its previous update was forwarded
+        if let Some(delivered) = self.delivered.take() {
+            let _ = delivered.send(());
+        }
+        let (update, delivered) = self.updates.recv().await?;
+        self.delivered = Some(delivered);
+        Some(update)
+    }
+}
@@ -0,0 +1,106 @@
+pub mod aoe2lobby;
+#[cfg(test)]
+pub mod fake;
+pub mod replay;
+
+use crate::lobby_cache::capture::CaptureWriter;
+use crate::lobby_cache::config::LobbyCacheConfig;
+use crate::lobby_cache::dead_letter::DeadLetterWriter;
+use crate::lobby_cache::model::Lobby;
+use crate::lobby_cache::reconnect::ReconnectStatus;
+use crate::lobby_cache::source::aoe2lobby::Aoe2LobbySource;
+use crate::lobby_cache::source::replay::ReplaySource;
+use serenity::async_trait;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tracing::{error, info};
+
+// Normalized updates every lobby provider produces
+#[derive(Debug, Clone, PartialEq)]
+pub enum SourceUpdate {
+    // Replaces every lobby previously received from the source, except the dropped ones
+    Snapshot {
+        lobbies: HashMap<String, Arc<Lobby>>,
+        // Ids of lobbies that were sent but didn't parse, their last known state is kept
+        dropped: Vec<String>,
+    },
+    Delta {
+        updated: HashMap<String, Arc<Lobby>>,
+        deleted: Vec<i64>,
+        // Ids of updated lobbies that didn't parse, their last known state is kept
+        dropped: Vec<String>,
+    },
+    Connected,
+    // The source lost its connection, its lobbies can't be trusted until its next snapshot
+    Disconnected(ReconnectStatus),
+}
+
+impl SourceUpdate {
+    pub fn dropped_lobbies(&self) -> &[String] {
+        match self {
+            SourceUpdate::Snapshot { dropped, .. } | SourceUpdate::Delta { dropped, .. } => dropped,
+            SourceUpdate::Connected | SourceUpdate::Disconnected(_) => &[],
+        }
+    }
+}
+
+#[async_trait]
+pub trait LobbySource: Send {
+    fn name(&self) -> &str;
+
+    // Waits for the next update, returns None once the source has nothing more to offer
+    async fn next_update(&mut self) -> Option<SourceUpdate>;
+}
+
+// Builds the configured sources, ordered from most to least preferred
+pub fn from_config(config: &LobbyCacheConfig) -> Vec<Box<dyn LobbySource>> {
+    if let Some(replay_path) = &config.replay_path {
+        return vec![Box::new(ReplaySource::new(
+            replay_path.clone(),
+            config.replay_speed,
+        ))];
+    }
+
+    let mut primary = Aoe2LobbySource::new("aoe2lobby", config.url.clone(), config);
+    if let Some(capture_path) = &config.capture_path {
+        match CaptureWriter::open(capture_path) {
+            Ok(capture) => {
+                info!("Capturing websocket frames to {}", capture_path.display());
+                primary = primary.with_capture(capture);
+            }
+            Err(e) => error!(
+                "Failed to open capture file {}: {:?}",
+                capture_path.display(),
+                e
+            ),
+        }
+    }
+
+    let mut fallback = config.fallback_url.as_ref().map(|fallback_url| {
+        Aoe2LobbySource::new("aoe2lobby-fallback", fallback_url.clone(), config)
+    });
+    if let Some(dead_letter_path) = &config.dead_letter_path {
+        match DeadLetterWriter::open(dead_letter_path, config.dead_letter_max_files) {
+            Ok(dead_letters) => {
+                info!(
+                    "Writing unparseable frames to {}",
+                    dead_letter_path.display()
+                );
+                let dead_letters = Arc::new(dead_letters);
+                primary = primary.with_dead_letters(dead_letters.clone());
+                fallback = fallback.map(|fallback| fallback.with_dead_letters(dead_letters));
+            }
+            Err(e) => error!(
+                "Failed to open dead letter directory {}: {:?}",
+                dead_letter_path.display(),
+                e
+            ),
+        }
+    }
+
+    let mut sources: Vec<Box<dyn LobbySource>> = vec![Box::new(primary)];
+    if let Some(fallback) = fallback {
+        sources.push(Box::new(fallback));
+    }
+    sources
+}
@@ -0,0 +1,85 @@
+use crate::lobby_cache::capture::{read_capture, CapturedFrame};
+use crate::lobby_cache::source::aoe2lobby::parse_message;
+use crate::lobby_cache::source::{LobbySource, SourceUpdate};
+use serenity::async_trait;
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use tokio::time::{sleep, Duration};
+use tracing::{error, info};
+
+// Feeds a capture file back, keeping the original pacing scaled by speed
+pub struct ReplaySource {
+    path: PathBuf,
+    speed: f64,
+    frames: Option<VecDeque<CapturedFrame>>,
+    previous_timestamp: Option<u64>,
+}
+
+impl ReplaySource {
+    pub fn new(path: PathBuf, speed: f64) -> Self {
+        Self {
+            path,
+            speed,
+            frames: None,
+            previous_timestamp: None,
+        }
+    }
+
+    fn load(&self) -> VecDeque<CapturedFrame> {
+        match read_capture(&self.path) {
+            Ok(frames) => {
+                info!(
+                    "Replaying {} frames from {}",
+                    frames.len(),
+                    self.path.display()
+                );
+                frames.into()
+            }
+            Err(e) => {
+                error!(
+                    "Failed to read capture file {}: {:?}",
+                    self.path.display(),
+                    e
+                );
+                VecDeque::new()
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl LobbySource for ReplaySource {
+    fn name(&self) -> &str {
+        "replay"
+    }
+
+    async fn next_update(&mut self) -> Option<SourceUpdate> {
+        if self.frames.is_none() {
+            self.frames = Some(self.load());
+        }
+
+        loop {
+            let frame = match self.frames.as_mut().and_then(VecDeque::pop_front) {
+                Some(frame) => frame,
+                None => {
+                    info!("Replay finished");
+                    return None;
+                }
+            };
+
+            let elapsed = frame
+                .timestamp
+                .saturating_sub(self.previous_timestamp.unwrap_or(frame.timestamp));
+            self.previous_timestamp = Some(frame.timestamp);
+            if self.speed > 0.0 {
+                sleep(Duration::from_millis(elapsed).div_f64(self.speed)).await;
+            }
+
+            match parse_message(&frame.message.into_message()) {
+                Ok(Some(update)) => return Some(update),
+                Ok(None) => {}
+                Err(e) => error!("Error handling message: {:?}", e),
+            }
+        }
+    }
+}
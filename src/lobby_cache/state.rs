@@ -0,0 +1,42 @@
+use crate::lobby_cache::reconnect::ReconnectStatus;
+use std::fmt;
+
+// Where the lobby cache stands with its upstream sources
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionState {
+    // No source connected yet
+    Connecting,
+    // A source is connected, waiting for its first full snapshot
+    Synchronizing,
+    // Lobbies come from the preferred source
+    Live,
+    // Lobbies are served, but not from the preferred source
+    Degraded { reason: String },
+    // Every source is down, status describes the preferred one
+    Disconnected(ReconnectStatus),
+}
+
+impl ConnectionState {
+    // Whether lobbies are being served from a source or restored data
+    pub fn is_serving(&self) -> bool {
+        matches!(
+            self,
+            ConnectionState::Live | ConnectionState::Degraded { .. }
+        )
+    }
+}
+
+impl fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionState::Connecting => write!(f, "connecting"),
+            ConnectionState::Synchronizing => write!(f, "synchronizing"),
+            ConnectionState::Live => write!(f, "live"),
+            ConnectionState::Degraded { reason } => write!(f, "degraded, {}", reason),
+            ConnectionState::Disconnected(status) => match &status.last_error {
+                Some(last_error) => write!(f, "disconnected, {}", last_error),
+                None => write!(f, "disconnected"),
+            },
+        }
+    }
+}
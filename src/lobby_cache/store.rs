@@ -0,0 +1,432 @@
+use crate::lobby_cache::event::{DeletionReason, LobbyEvent};
+use crate::lobby_cache::index::{LobbyIndex, LobbyQuery};
+use crate::lobby_cache::model::Lobby;
+use crate::lobby_cache::state::ConnectionState;
+use dashmap::DashMap;
+use serenity::async_trait;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock as StdRwLock};
+use std::time::SystemTime;
+use tokio::sync::{broadcast, watch, Mutex as TokioMutex};
+use tokio::time::Duration;
+use tracing::debug;
+
+// Read side of the lobby cache, what commands depend on
+#[async_trait]
+pub trait LobbyStore: Send + Sync {
+    // Lobbies are shared with the store, reading them never copies
+    fn get(&self, lobby_id: &str) -> Option<Arc<Lobby>>;
+
+    fn list(&self) -> Vec<Arc<Lobby>>;
+
+    // Returns the lobbies matching the query, looked up through the secondary indexes
+    fn search(&self, query: &LobbyQuery) -> Vec<Arc<Lobby>>;
+
+    // Returns a receiver that only changes when the given lobby is updated or removed
+    fn watch(&self, lobby_id: &str) -> watch::Receiver<WatchedLobby>;
+
+    fn subscribe(&self) -> broadcast::Receiver<LobbyEvent>;
+
+    // Whether the lobbies can be served to users
+    async fn is_fresh(&self) -> bool;
+
+    // Set while the store only holds lobbies restored from a snapshot
+    fn is_provisional(&self) -> bool;
+
+    fn connection_state(&self) -> ConnectionState;
+
+    fn watch_connection_state(&self) -> watch::Receiver<ConnectionState>;
+}
+
+// What watchers of a lobby see
+#[derive(Debug, Clone, PartialEq)]
+pub enum WatchedLobby {
+    // Not cached, upstream may not have picked the lobby up yet
+    Missing,
+    Active(Arc<Lobby>),
+    // The last known state of the lobby before it disappeared
+    Deleted {
+        lobby: Arc<Lobby>,
+        reason: DeletionReason,
+    },
+}
+
+impl WatchedLobby {
+    fn from_cached(lobby: Option<Arc<Lobby>>) -> Self {
+        match lobby {
+            Some(lobby) => WatchedLobby::Active(lobby),
+            None => WatchedLobby::Missing,
+        }
+    }
+}
+
+// Lobbies kept in memory, fed through apply. Backs LobbyCache, and can be driven directly where
+// no upstream is wanted
+pub struct MemoryLobbyStore {
+    lobbies: DashMap<String, Arc<Lobby>>,
+    // Only written together with lobbies, in apply and restore
+    index: StdRwLock<LobbyIndex>,
+    last_update: TokioMutex<Option<SystemTime>>,
+    provisional: AtomicBool,
+    update_broadcast_sender: broadcast::Sender<LobbyEvent>,
+    watchers: DashMap<String, watch::Sender<WatchedLobby>>,
+    // Lobbies are stale when they weren't updated for this long
+    stale_after: Duration,
+    // Restored lobbies are stale when the snapshot is older than this
+    provisional_max_age: Duration,
+}
+
+impl MemoryLobbyStore {
+    pub fn new(stale_after: Duration, provisional_max_age: Duration) -> Self {
+        Self {
+            lobbies: DashMap::new(),
+            index: StdRwLock::new(LobbyIndex::default()),
+            last_update: TokioMutex::new(None),
+            provisional: AtomicBool::new(false),
+            // Full snapshots can carry hundreds of lobbies, so leave room for slow subscribers
+            update_broadcast_sender: broadcast::channel(1024).0,
+            watchers: DashMap::new(),
+            stale_after,
+            provisional_max_age,
+        }
+    }
+
+    pub fn lobby_count(&self) -> usize {
+        self.lobbies.len()
+    }
+
+    pub fn lobbies(&self) -> HashMap<String, Arc<Lobby>> {
+        self.lobbies
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect()
+    }
+
+    pub async fn last_update(&self) -> Option<SystemTime> {
+        *self.last_update.lock().await
+    }
+
+    // Serves lobbies restored from a snapshot until the next full update
+    pub async fn restore(&self, lobbies: HashMap<String, Arc<Lobby>>, taken_at: SystemTime) {
+        {
+            let mut index = self.index.write().unwrap();
+            for (lobby_id, lobby) in lobbies {
+                index.insert(&lobby_id, &lobby);
+                self.lobbies.insert(lobby_id, lobby);
+            }
+        }
+        self.provisional.store(true, Ordering::SeqCst);
+        self.last_update.lock().await.replace(taken_at);
+    }
+
+    // Overwrites and deletes lobbies, or replaces all of them when reset is set, then notifies
+    // watchers and subscribers
+    pub async fn apply(
+        &self,
+        overwrite_lobbies: HashMap<String, Arc<Lobby>>,
+        delete_lobbies: Vec<i64>,
+        reset: bool,
+    ) {
+        // The first full snapshot replaces restored lobbies, even the ones that didn't change
+        let was_provisional = reset && self.provisional.swap(false, Ordering::SeqCst);
+        // The index guard must be released before awaiting
+        let mut events = vec![];
+        {
+            let mut index = self.index.write().unwrap();
+            if reset {
+                self.lobbies.clear();
+                index.clear();
+            }
+            for (lobby_id, lobby) in overwrite_lobbies {
+                let previous = self.lobbies.insert(lobby_id.clone(), lobby.clone());
+                if let Some(previous) = &previous {
+                    index.remove(&lobby_id, previous);
+                }
+                index.insert(&lobby_id, &lobby);
+                match previous {
+                    _ if reset => {}
+                    Some(previous) if previous == lobby => {}
+                    Some(previous) => events.push(LobbyEvent::Updated {
+                        previous,
+                        current: lobby,
+                    }),
+                    None => events.push(LobbyEvent::Created(lobby)),
+                }
+            }
+            for lobby_id in delete_lobbies {
+                if let Some((lobby_id, lobby)) = self.lobbies.remove(&lobby_id.to_string()) {
+                    index.remove(&lobby_id, &lobby);
+                    let reason = DeletionReason::classify(&lobby);
+                    events.push(LobbyEvent::Deleted { lobby, reason });
+                }
+            }
+        }
+        if reset {
+            events.push(LobbyEvent::Reset);
+        }
+
+        if !events.is_empty() {
+            debug!("Sending {} update broadcasts", events.len());
+        }
+        if reset {
+            for watcher in self.watchers.iter() {
+                // Lobbies missing from the snapshot closed while the source was out of sync
+                let lobby = match (self.get(watcher.key()), &*watcher.value().borrow()) {
+                    (None, WatchedLobby::Active(previous)) => WatchedLobby::Deleted {
+                        lobby: previous.clone(),
+                        reason: DeletionReason::classify(previous),
+                    },
+                    (None, watched) => watched.clone(),
+                    (Some(lobby), _) => WatchedLobby::Active(lobby),
+                };
+                if was_provisional {
+                    watcher.value().send_replace(lobby);
+                } else {
+                    Self::publish(watcher.value(), lobby);
+                }
+            }
+        } else {
+            for event in &events {
+                match event {
+                    LobbyEvent::Created(lobby) | LobbyEvent::Updated { current: lobby, .. } => self
+                        .notify_watcher(
+                            &lobby.lobbyid.to_string(),
+                            WatchedLobby::Active(lobby.clone()),
+                        ),
+                    LobbyEvent::Deleted { lobby, reason } => self.notify_watcher(
+                        &lobby.lobbyid.to_string(),
+                        WatchedLobby::Deleted {
+                            lobby: lobby.clone(),
+                            reason: *reason,
+                        },
+                    ),
+                    LobbyEvent::Reset => {}
+                }
+            }
+        }
+        self.watchers
+            .retain(|_, sender| sender.receiver_count() > 0);
+
+        for event in events {
+            // Sending only fails when there are no subscribers
+            let _ = self.update_broadcast_sender.send(event);
+        }
+
+        self.last_update.lock().await.replace(SystemTime::now());
+    }
+
+    fn notify_watcher(&self, lobby_id: &str, lobby: WatchedLobby) {
+        if let Some(sender) = self.watchers.get(lobby_id) {
+            Self::publish(&sender, lobby);
+        }
+    }
+
+    fn publish(sender: &watch::Sender<WatchedLobby>, lobby: WatchedLobby) {
+        sender.send_if_modified(|current| {
+            if *current == lobby {
+                return false;
+            }
+            *current = lobby;
+            true
+        });
+    }
+}
+
+#[async_trait]
+impl LobbyStore for MemoryLobbyStore {
+    fn get(&self, lobby_id: &str) -> Option<Arc<Lobby>> {
+        self.lobbies
+            .get(lobby_id)
+            .map(|lobby_ref| lobby_ref.clone())
+    }
+
+    fn list(&self) -> Vec<Arc<Lobby>> {
+        self.lobbies
+            .iter()
+            .map(|entry| entry.value().clone())
+            .collect()
+    }
+
+    fn search(&self, query: &LobbyQuery) -> Vec<Arc<Lobby>> {
+        let lobby_ids = self.index.read().unwrap().search(query);
+        lobby_ids
+            .iter()
+            .filter_map(|lobby_id| self.get(lobby_id))
+            .collect()
+    }
+
+    fn watch(&self, lobby_id: &str) -> watch::Receiver<WatchedLobby> {
+        // Holding the watcher entry while reading the lobbies guarantees that a concurrent update
+        // either lands before the initial value is read, or is delivered through the sender
+        self.watchers
+            .entry(lobby_id.to_string())
+            .or_insert_with(|| watch::channel(WatchedLobby::from_cached(self.get(lobby_id))).0)
+            .subscribe()
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<LobbyEvent> {
+        self.update_broadcast_sender.subscribe()
+    }
+
+    async fn is_fresh(&self) -> bool {
+        let max_age = if self.is_provisional() {
+            self.provisional_max_age
+        } else {
+            self.stale_after
+        };
+        match *self.last_update.lock().await {
+            Some(last_update) => last_update.elapsed().unwrap_or_default() <= max_age,
+            None => false,
+        }
+    }
+
+    fn is_provisional(&self) -> bool {
+        self.provisional.load(Ordering::SeqCst)
+    }
+
+    // Nothing to connect to, every applied update is live
+    fn connection_state(&self) -> ConnectionState {
+        ConnectionState::Live
+    }
+
+    fn watch_connection_state(&self) -> watch::Receiver<ConnectionState> {
+        watch::channel(ConnectionState::Live).1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lobby(lobby_id: i64, slotstaken: i64) -> Arc<Lobby> {
+        Arc::new(Lobby {
+            lobbyid: lobby_id,
+            relayserver_region: "westeurope".into(),
+            slotstaken,
+            slotstotal: 4,
+            ..Lobby::default()
+        })
+    }
+
+    fn lobbies(lobbies: &[Arc<Lobby>]) -> HashMap<String, Arc<Lobby>> {
+        lobbies
+            .iter()
+            .map(|lobby| (lobby.lobbyid.to_string(), lobby.clone()))
+            .collect()
+    }
+
+    fn store() -> MemoryLobbyStore {
+        MemoryLobbyStore::new(Duration::from_secs(60), Duration::from_secs(600))
+    }
+
+    fn drain(receiver: &mut broadcast::Receiver<LobbyEvent>) -> Vec<LobbyEvent> {
+        let mut events = vec![];
+        while let Ok(event) = receiver.try_recv() {
+            events.push(event);
+        }
+        events
+    }
+
+    #[tokio::test]
+    async fn broadcasts_creations_updates_and_deletions() {
+        let store = store();
+        let mut receiver = store.subscribe();
+
+        store.apply(lobbies(&[lobby(1, 1)]), vec![], false).await;
+        store.apply(lobbies(&[lobby(1, 1)]), vec![], false).await;
+        store.apply(lobbies(&[lobby(1, 2)]), vec![], false).await;
+        store.apply(HashMap::new(), vec![1, 2], false).await;
+
+        assert_eq!(
+            drain(&mut receiver),
+            vec![
+                LobbyEvent::Created(lobby(1, 1)),
+                LobbyEvent::Updated {
+                    previous: lobby(1, 1),
+                    current: lobby(1, 2),
+                },
+                LobbyEvent::Deleted {
+                    lobby: lobby(1, 2),
+                    reason: DeletionReason::classify(&lobby(1, 2)),
+                },
+            ]
+        );
+        assert!(store.get("1").is_none());
+    }
+
+    #[tokio::test]
+    async fn watchers_only_see_their_lobby() {
+        let store = store();
+        let mut watched = store.watch("1");
+        assert_eq!(*watched.borrow_and_update(), WatchedLobby::Missing);
+
+        store.apply(lobbies(&[lobby(2, 1)]), vec![], false).await;
+        assert!(!watched.has_changed().unwrap());
+
+        store.apply(lobbies(&[lobby(1, 1)]), vec![], false).await;
+        assert_eq!(
+            *watched.borrow_and_update(),
+            WatchedLobby::Active(lobby(1, 1))
+        );
+        store.apply(HashMap::new(), vec![1], false).await;
+        assert!(matches!(
+            &*watched.borrow_and_update(),
+            WatchedLobby::Deleted { lobby, .. } if lobby.slotstaken == 1
+        ));
+    }
+
+    #[tokio::test]
+    async fn resets_replace_every_lobby() {
+        let store = store();
+        store
+            .apply(lobbies(&[lobby(1, 1), lobby(2, 1)]), vec![], false)
+            .await;
+        let mut watched = store.watch("1");
+        let mut receiver = store.subscribe();
+
+        store.apply(lobbies(&[lobby(2, 3)]), vec![], true).await;
+        assert_eq!(drain(&mut receiver), vec![LobbyEvent::Reset]);
+        assert_eq!(store.list(), vec![lobby(2, 3)]);
+        // Lobbies missing from the snapshot closed in the meantime
+        assert!(matches!(
+            &*watched.borrow_and_update(),
+            WatchedLobby::Deleted { lobby, .. } if lobby.lobbyid == 1
+        ));
+    }
+
+    #[tokio::test]
+    async fn serves_restored_lobbies_until_the_first_reset() {
+        let store = store();
+        assert!(!store.is_fresh().await);
+
+        let taken_at = SystemTime::now() - Duration::from_secs(120);
+        store.restore(lobbies(&[lobby(1, 1)]), taken_at).await;
+        assert!(store.is_provisional());
+        // Older than stale_after, but within the provisional max age
+        assert!(store.is_fresh().await);
+        assert_eq!(store.last_update().await, Some(taken_at));
+
+        store.apply(lobbies(&[lobby(2, 1)]), vec![], false).await;
+        assert!(store.is_provisional());
+        store.apply(lobbies(&[lobby(2, 1)]), vec![], true).await;
+        assert!(!store.is_provisional());
+        assert!(store.get("1").is_none());
+    }
+
+    #[tokio::test]
+    async fn searches_through_the_indexes() {
+        let store = store();
+        store
+            .apply(lobbies(&[lobby(1, 1), lobby(2, 4)]), vec![], false)
+            .await;
+        let query = LobbyQuery {
+            min_open_slots: Some(1),
+            ..LobbyQuery::default()
+        };
+        assert_eq!(store.search(&query), vec![lobby(1, 1)]);
+
+        store.apply(lobbies(&[lobby(1, 4)]), vec![], false).await;
+        assert!(store.search(&query).is_empty());
+    }
+}
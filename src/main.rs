@@ -1,26 +1,45 @@
+mod bench;
+mod cli;
 mod commands;
+mod health;
 mod lobby_cache;
+mod metrics;
+#[cfg(test)]
+mod mock_upstream;
 
 use std::env;
 use std::env::VarError;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
 use futures::future::join_all;
 use serenity::async_trait;
 use serenity::model::application::command::Command;
-use std::sync::atomic::Ordering;
 
 use tokio::signal;
 
+use crate::commands::history::HistoryHandler;
 use crate::commands::lobby::LobbyHandler;
+use crate::commands::scheduler::EditScheduler;
+use crate::commands::search::SearchHandler;
+use crate::commands::tracked::TrackedStore;
 use crate::commands::util::create_interaction_response;
+use crate::health::HealthState;
+use crate::lobby_cache::config::LobbyCacheConfig;
+use crate::lobby_cache::state::ConnectionState;
+use crate::lobby_cache::store::LobbyStore;
 use crate::lobby_cache::LobbyCache;
 use serenity::model::application::interaction::Interaction;
-use serenity::model::gateway::Ready;
+use serenity::model::gateway::{Activity, Ready};
 use serenity::model::id::GuildId;
+use serenity::model::user::OnlineStatus;
 
 use serenity::prelude::*;
 use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
 use tracing::log::debug;
 use tracing::subscriber::set_global_default;
 use tracing::{error, info, warn};
@@ -31,6 +50,11 @@ use tracing_subscriber::{EnvFilter, Registry};
 
 struct Handler {
     lobby_handler: Arc<LobbyHandler>,
+    search_handler: Arc<SearchHandler>,
+    history_handler: Arc<HistoryHandler>,
+    lobby_cache: Arc<LobbyCache>,
+    // Ready fires again after every gateway reconnect, background tasks only start once
+    background_started: AtomicBool,
     guild_ids: Vec<GuildId>,
 }
 
@@ -54,11 +78,71 @@ impl Handler {
             }
         };
 
+        let tracked_store =
+            TrackedStore::open(env::var_os("TRACKED_LOBBIES_FILE").map(PathBuf::from));
+        let edit_interval = env::var("DISCORD_EDIT_INTERVAL_SECS")
+            .map(|secs| {
+                secs.parse()
+                    .expect("DISCORD_EDIT_INTERVAL_SECS must be a number of seconds")
+            })
+            .unwrap_or(1);
+        let scheduler = EditScheduler::new(Duration::from_secs(edit_interval));
+
         Self {
-            lobby_handler: Arc::new(LobbyHandler::new(lobby_cache)),
+            lobby_handler: Arc::new(LobbyHandler::new(
+                lobby_cache.clone(),
+                tracked_store,
+                scheduler,
+            )),
+            search_handler: Arc::new(SearchHandler::new(lobby_cache.clone())),
+            history_handler: Arc::new(HistoryHandler::new(lobby_cache.history())),
+            lobby_cache,
+            background_started: AtomicBool::new(false),
             guild_ids,
         }
     }
+
+    // Verify that the interaction is coming from an expected guild, every guild is when none is
+    // configured
+    fn is_expected_guild(&self, guild_id: Option<GuildId>, kind: &str) -> bool {
+        if self.guild_ids.is_empty() {
+            return true;
+        }
+        match guild_id {
+            Some(guild_id) if self.guild_ids.contains(&guild_id) => true,
+            Some(guild_id) => {
+                warn!(
+                    "Received {} interaction from unexpected guild: {}",
+                    kind, guild_id
+                );
+                false
+            }
+            None => {
+                warn!("Received {} interaction without guild id", kind);
+                false
+            }
+        }
+    }
+}
+
+// Shows the lobby cache connection state as the bot status
+async fn set_presence(ctx: &Context, state: &ConnectionState) {
+    let (activity, status) = match state {
+        ConnectionState::Live => (Activity::watching("aoe2 lobbies"), OnlineStatus::Online),
+        ConnectionState::Degraded { .. } => (
+            Activity::watching("aoe2 lobbies (degraded)"),
+            OnlineStatus::Idle,
+        ),
+        ConnectionState::Connecting | ConnectionState::Synchronizing => (
+            Activity::playing("connecting to aoe2lobby.com"),
+            OnlineStatus::Idle,
+        ),
+        ConnectionState::Disconnected(_) => (
+            Activity::playing("aoe2lobby.com is unreachable"),
+            OnlineStatus::DoNotDisturb,
+        ),
+    };
+    ctx.set_presence(Some(activity), status).await;
 }
 
 #[async_trait]
@@ -66,11 +150,32 @@ impl EventHandler for Handler {
     async fn ready(&self, ctx: Context, ready: Ready) {
         info!("{} is connected!", ready.user.name);
 
+        if !self.background_started.swap(true, Ordering::SeqCst) {
+            tokio::spawn(self.lobby_handler.clone().resume(ctx.clone()));
+
+            let mut connection_state = self.lobby_cache.watch_connection_state();
+            let ctx = ctx.clone();
+            tokio::spawn(async move {
+                loop {
+                    let state = connection_state.borrow_and_update().clone();
+                    set_presence(&ctx, &state).await;
+                    if connection_state.changed().await.is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+
         if self.guild_ids.is_empty() {
             info!("Running in global mode");
             let _commands = Command::set_global_application_commands(&ctx.http, |commands| {
-                commands.create_application_command(|command| LobbyHandler::register(command))
-            }).await.expect("Failed to register application commands");
+                commands
+                    .create_application_command(|command| LobbyHandler::register(command))
+                    .create_application_command(|command| SearchHandler::register(command))
+                    .create_application_command(|command| HistoryHandler::register(command))
+            })
+            .await
+            .expect("Failed to register application commands");
         } else {
             info!("Running in guild mode");
             for guild_id in &self.guild_ids {
@@ -78,6 +183,8 @@ impl EventHandler for Handler {
                     GuildId::set_application_commands(guild_id, &ctx.http, |commands| {
                         commands
                             .create_application_command(|command| LobbyHandler::register(command))
+                            .create_application_command(|command| SearchHandler::register(command))
+                            .create_application_command(|command| HistoryHandler::register(command))
                     })
                     .await
                     .expect("Failed to register application commands");
@@ -89,39 +196,44 @@ impl EventHandler for Handler {
         if let Interaction::ApplicationCommand(command) = interaction {
             debug!("Received command interaction: {:#?}", command);
 
-            //Verify that the interaction is a command is coming for expected guild
-            if !self.guild_ids.is_empty() {
-                match command.guild_id {
-                    Some(guild_id) => {
-                        if !self.guild_ids.contains(&guild_id) {
-                            warn!(
-                                "Received command interaction from unexpected guild: {}",
-                                guild_id
-                            );
-                            return;
-                        }
-                    }
-                    None => {
-                        warn!("Received command interaction without guild id");
-                        return;
-                    }
-                }
+            if !self.is_expected_guild(command.guild_id, "command") {
+                return;
             }
             match command.data.name.as_str() {
                 "lobby" => {
                     self.lobby_handler.run(&ctx, command).await;
                 }
+                "search" => {
+                    self.search_handler.run(&ctx, command).await;
+                }
+                "history" => {
+                    self.history_handler.run(&ctx, command).await;
+                }
                 _ => {
                     create_interaction_response(&ctx, command, "not implemented :(".to_string())
                         .await;
                 }
             };
+        } else if let Interaction::MessageComponent(component) = interaction {
+            debug!("Received component interaction: {:#?}", component);
+            if !self.is_expected_guild(component.guild_id, "component") {
+                return;
+            }
+            if component.data.custom_id.starts_with("lobby:") {
+                self.lobby_handler.run_component(&ctx, component).await;
+            } else {
+                warn!("Unknown component: {}", component.data.custom_id);
+            }
         }
     }
 }
 
 #[tokio::main]
 async fn main() {
+    if let Some(code) = cli::run(&env::args().collect::<Vec<_>>()).await {
+        std::process::exit(code);
+    }
+
     let mut filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
     let formatting_layer = BunyanFormattingLayer::new("lobby_is_up".to_string(), std::io::stdout);
     for (log_directive, directive_level) in [
@@ -151,18 +263,20 @@ async fn main() {
         }
     }
 
+    metrics::init();
+
     let (shutdown_send, mut shutdown_recv) = mpsc::unbounded_channel();
 
-    // Get lobby cache singleton
-    let lobby_cache = lobby_cache::LobbyCacheOnce::new();
-    let lobby_cache = lobby_cache.get_instance();
+    let lobby_cache = Arc::new(LobbyCache::new(LobbyCacheConfig::from_env()));
+    let lobby_cache_cancel = CancellationToken::new();
 
     let shutdown_lobby_cache_clone = shutdown_send.clone();
     let lobby_cache_shared = lobby_cache.clone();
+    let lobby_cache_cancel_clone = lobby_cache_cancel.clone();
 
     // Start the lobby cache background task
     let _lobby_cache_task = tokio::spawn(async move {
-        lobby_cache_shared.run().await;
+        lobby_cache_shared.run(lobby_cache_cancel_clone).await;
         warn!("Lobby cache shutdown");
         shutdown_lobby_cache_clone.send(()).unwrap();
     });
@@ -177,6 +291,14 @@ async fn main() {
         .expect("Error creating client");
 
     let shard_manager = client.shard_manager.clone();
+
+    let health_addr: SocketAddr = env::var("HTTP_ADDR")
+        .unwrap_or_else(|_| "0.0.0.0:8080".to_string())
+        .parse()
+        .expect("HTTP_ADDR must be a socket address");
+    let shard_runners = shard_manager.lock().await.runners.clone();
+    let health_state = Arc::new(HealthState::new(lobby_cache.clone(), shard_runners));
+    let _health_task = tokio::spawn(health::serve(health_addr, health_state));
     // Finally, start a single shard, and start listening to events.
     //
     // Shards will automatically attempt to reconnect, and will perform
@@ -202,10 +324,7 @@ async fn main() {
     }
 
     warn!("Received shutdown signal");
-    if lobby_cache.running.load(Ordering::SeqCst) {
-        let shutdown_signal = lobby_cache.shutdown.lock().await;
-        shutdown_signal.send(()).await.unwrap();
-    }
+    lobby_cache_cancel.cancel();
     shard_manager.lock().await.shutdown_all().await;
 
     join_all(vec![_lobby_cache_task, _discord_task]).await;
@@ -0,0 +1,131 @@
+use crate::lobby_cache::LobbyCache;
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_gauge, register_int_counter, register_int_counter_vec, register_int_gauge, Encoder,
+    Gauge, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+use tracing::error;
+
+pub static CACHED_LOBBIES: Lazy<IntGauge> =
+    Lazy::new(|| register_int_gauge!("lobby_cache_lobbies", "Number of cached lobbies").unwrap());
+
+pub static SECONDS_SINCE_LAST_UPDATE: Lazy<Gauge> = Lazy::new(|| {
+    register_gauge!(
+        "lobby_cache_seconds_since_last_update",
+        "Seconds since upstream last updated the lobby cache, -1 if it never did"
+    )
+    .unwrap()
+});
+
+pub static FRAMES_RECEIVED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "upstream_frames_received_total",
+        "Websocket frames received from upstream by type, unknown frames by their top-level key",
+        &["kind"]
+    )
+    .unwrap()
+});
+
+pub static PARSE_FAILURES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "upstream_parse_failures_total",
+        "Upstream frames that couldn't be parsed"
+    )
+    .unwrap()
+});
+
+pub static DROPPED_LOBBIES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "upstream_dropped_lobbies_total",
+        "Lobbies left out of an upstream frame because they couldn't be parsed"
+    )
+    .unwrap()
+});
+
+pub static DEAD_LETTERS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "upstream_dead_letters_total",
+        "Unparseable upstream frames written to the dead letter directory"
+    )
+    .unwrap()
+});
+
+pub static RECONNECTS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "upstream_reconnects_total",
+        "Reconnection attempts to the upstream websocket"
+    )
+    .unwrap()
+});
+
+pub static RESYNCS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "upstream_resyncs_total",
+        "Reconnections forced to receive a fresh full snapshot, by reason",
+        &["reason"]
+    )
+    .unwrap()
+});
+
+pub static SNAPSHOT_DRIFT: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "lobby_cache_snapshot_drift_lobbies",
+        "Lobbies that differed between the delta built cache and the last full snapshot"
+    )
+    .unwrap()
+});
+
+pub static TRACKED_INTERACTIONS: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "discord_tracked_interactions",
+        "Lobby messages currently updated live"
+    )
+    .unwrap()
+});
+
+pub static DISCORD_EDIT_FAILURES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "discord_edit_failures_total",
+        "Failed attempts to create or edit a Discord response"
+    )
+    .unwrap()
+});
+
+pub static DISCORD_EDIT_RETRIES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "discord_edit_retries_total",
+        "Discord edits retried after a rate limit or a transient failure"
+    )
+    .unwrap()
+});
+
+// Registers every metric up front, so they are exported before their first update
+pub fn init() {
+    Lazy::force(&CACHED_LOBBIES);
+    Lazy::force(&SECONDS_SINCE_LAST_UPDATE);
+    Lazy::force(&FRAMES_RECEIVED);
+    Lazy::force(&PARSE_FAILURES);
+    Lazy::force(&DROPPED_LOBBIES);
+    Lazy::force(&DEAD_LETTERS);
+    Lazy::force(&RECONNECTS);
+    Lazy::force(&RESYNCS);
+    Lazy::force(&SNAPSHOT_DRIFT);
+    Lazy::force(&TRACKED_INTERACTIONS);
+    Lazy::force(&DISCORD_EDIT_FAILURES);
+    Lazy::force(&DISCORD_EDIT_RETRIES);
+}
+
+// Refreshes the gauges that are derived from the cache and encodes all metrics
+pub async fn render(lobby_cache: &LobbyCache) -> String {
+    CACHED_LOBBIES.set(lobby_cache.lobby_count() as i64);
+    SECONDS_SINCE_LAST_UPDATE.set(match lobby_cache.last_update().await {
+        Some(last_update) => last_update.elapsed().unwrap_or_default().as_secs_f64(),
+        None => -1.0,
+    });
+
+    let mut buffer = vec![];
+    if let Err(e) = TextEncoder::new().encode(&prometheus::gather(), &mut buffer) {
+        error!("Failed to encode metrics: {:?}", e);
+    }
+    String::from_utf8(buffer).unwrap_or_default()
+}
@@ -0,0 +1,123 @@
+// Scripted websocket server standing in for aoe2lobby.com. Backs the mock_aoe2lobby binary, where
+// the script format is documented, and is started in-process by the lobby cache tests
+
+use std::net::SocketAddr;
+
+use futures::{SinkExt, StreamExt};
+use serde_derive::Deserialize;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{sleep, Duration};
+use tokio_tungstenite::accept_async;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{error, info, warn};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Step {
+    Send(serde_json::Value),
+    SendRaw(String),
+    SleepMs(u64),
+    Drop,
+    Close,
+    Silence,
+    NextConnection,
+}
+
+// Splits a script into the steps of each connection, name is only used in error messages
+pub fn parse_script(name: &str, content: &str) -> Vec<Vec<Step>> {
+    let mut connections = vec![vec![]];
+    for (line_number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with("//") {
+            continue;
+        }
+        let step: Step = serde_json::from_str(line)
+            .unwrap_or_else(|e| panic!("{}:{}: invalid step: {}", name, line_number + 1, e));
+        match step {
+            Step::NextConnection => connections.push(vec![]),
+            step => connections.last_mut().unwrap().push(step),
+        }
+    }
+    connections
+}
+
+// Accepts connections forever, playing the script's steps for each of them
+pub async fn run(listener: TcpListener, script: Vec<Vec<Step>>) {
+    let mut connection_count = 0;
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Failed to accept connection: {:?}", e);
+                continue;
+            }
+        };
+        let steps = script[connection_count.min(script.len() - 1)].clone();
+        info!("Connection #{} from {}", connection_count, peer);
+        connection_count += 1;
+        tokio::spawn(serve(stream, peer, steps));
+    }
+}
+
+async fn serve(stream: TcpStream, peer: SocketAddr, steps: Vec<Step>) {
+    let ws_stream = match accept_async(stream).await {
+        Ok(ws_stream) => ws_stream,
+        Err(e) => {
+            error!("Handshake with {} failed: {:?}", peer, e);
+            return;
+        }
+    };
+    let (mut write, mut read) = ws_stream.split();
+
+    // Drain incoming frames so pings are answered and disconnects are noticed
+    let reader = tokio::spawn(async move { while let Some(Ok(_)) = read.next().await {} });
+
+    for step in steps {
+        let result = match step {
+            Step::Send(frame) => write.send(Message::Text(frame.to_string())).await,
+            Step::SendRaw(text) => write.send(Message::Text(text)).await,
+            Step::SleepMs(ms) => {
+                sleep(Duration::from_millis(ms)).await;
+                Ok(())
+            }
+            Step::Drop => {
+                info!("Dropping connection to {}", peer);
+                reader.abort();
+                return;
+            }
+            Step::Close => {
+                info!("Closing connection to {}", peer);
+                let _ = write.send(Message::Close(None)).await;
+                let _ = reader.await;
+                return;
+            }
+            Step::Silence => break,
+            Step::NextConnection => unreachable!(),
+        };
+        if let Err(e) = result {
+            warn!("Connection to {} lost: {:?}", peer, e);
+            reader.abort();
+            return;
+        }
+    }
+
+    info!("Going silent on connection to {}", peer);
+    let _ = reader.await;
+    info!("{} disconnected", peer);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_example_script() {
+        let script = parse_script(
+            "mock/reconnect.jsonl",
+            include_str!("../mock/reconnect.jsonl"),
+        );
+        assert_eq!(script.len(), 3);
+        assert!(matches!(script[0].last(), Some(Step::Drop)));
+        assert!(matches!(script[1].last(), Some(Step::Silence)));
+    }
+}